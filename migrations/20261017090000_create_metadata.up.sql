@@ -0,0 +1,6 @@
+create table if not exists metadata (
+  name text primary key not null
+  , value blob not null
+  , created_at timestamp not null
+  , updated_at timestamp not null
+);
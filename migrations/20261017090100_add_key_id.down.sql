@@ -0,0 +1,2 @@
+alter table attributes drop column key_id;
+alter table entries drop column key_id;
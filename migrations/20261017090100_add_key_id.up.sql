@@ -0,0 +1,2 @@
+alter table entries add column key_id text;
+alter table attributes add column key_id text;
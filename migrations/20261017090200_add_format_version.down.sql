@@ -0,0 +1,2 @@
+alter table attributes drop column format_version;
+alter table entries drop column format_version;
@@ -0,0 +1,2 @@
+alter table entries add column format_version integer not null default 1;
+alter table attributes add column format_version integer not null default 1;
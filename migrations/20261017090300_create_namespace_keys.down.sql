@@ -0,0 +1,2 @@
+drop index if exists index_namespace_on_namespace_keys;
+drop table if exists namespace_keys;
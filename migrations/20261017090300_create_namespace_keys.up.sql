@@ -0,0 +1,12 @@
+create table if not exists namespace_keys (
+  id integer primary key autoincrement not null
+  , namespace text not null
+  , nonce blob not null
+  , wrapped_key blob not null
+  , key_id text not null
+  , data_key_id text not null
+  , created_at timestamp not null
+  , updated_at timestamp not null
+);
+
+create unique index if not exists index_namespace_on_namespace_keys on namespace_keys (namespace);
@@ -0,0 +1,2 @@
+alter table attributes drop column algorithm;
+alter table entries drop column algorithm;
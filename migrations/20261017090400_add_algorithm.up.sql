@@ -0,0 +1,2 @@
+alter table entries add column algorithm text not null default 'chacha20-poly1305';
+alter table attributes add column algorithm text not null default 'chacha20-poly1305';
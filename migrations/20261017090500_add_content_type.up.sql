@@ -0,0 +1 @@
+alter table entries add column content_type text;
@@ -0,0 +1 @@
+alter table entries drop column sliding_ttl_millis;
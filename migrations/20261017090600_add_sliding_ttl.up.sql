@@ -0,0 +1 @@
+alter table entries add column sliding_ttl_millis integer;
@@ -0,0 +1,34 @@
+create table attributes_new (
+  id integer primary key autoincrement not null
+  , entry_id integer not null references entries(id) on delete cascade
+  , name text not null
+  , nonce blob not null
+  , encrypted_value blob not null
+  , hashed_value blob not null
+  , created_at timestamp not null
+  , updated_at timestamp not null
+  , key_id text
+  , format_version integer not null default 1
+  , algorithm text not null default 'chacha20-poly1305'
+);
+
+insert into attributes_new select
+  id
+  , entry_id
+  , name
+  , nonce
+  , encrypted_value
+  , hashed_value
+  , created_at
+  , updated_at
+  , key_id
+  , format_version
+  , algorithm
+from attributes;
+
+drop table attributes;
+alter table attributes_new rename to attributes;
+
+create index if not exists index_entry_id_on_attributes on attributes (entry_id);
+create unique index if not exists index_entry_id_name_on_attributes on attributes (entry_id, name);
+create index if not exists index_name_and_encrypted_value_on_attributes on attributes (name, encrypted_value);
@@ -0,0 +1,2 @@
+drop index if exists index_name_and_encrypted_value_on_attributes;
+create index if not exists index_name_and_hashed_value_on_attributes on attributes (name, hashed_value);
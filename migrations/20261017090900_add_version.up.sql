@@ -0,0 +1 @@
+alter table entries add column version integer not null default 1;
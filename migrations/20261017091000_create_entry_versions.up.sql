@@ -0,0 +1,15 @@
+create table if not exists entry_versions (
+  id integer primary key autoincrement not null
+  , entry_id integer not null references entries(id) on delete cascade
+  , version integer not null
+  , nonce blob not null
+  , encrypted_value blob not null
+  , key_id text
+  , format_version integer not null
+  , algorithm text not null
+  , content_type text
+  , created_at timestamp not null
+  , archived_at timestamp not null
+);
+
+create unique index if not exists index_entry_id_version_on_entry_versions on entry_versions (entry_id, version);
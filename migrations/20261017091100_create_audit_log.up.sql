@@ -0,0 +1,11 @@
+create table if not exists audit_log (
+  id integer primary key autoincrement not null
+  , operation text not null
+  , namespace text not null
+  , key_name text not null
+  , pid integer not null
+  , executable text
+  , outcome text not null
+  , created_at timestamp not null
+  , hash blob
+);
@@ -0,0 +1,417 @@
+use chrono::{NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::SqliteConnection;
+
+use crate::error::CacheVaultError;
+use crate::key::KeyProvider;
+use crate::models::AuditLog;
+use crate::vault::Vault;
+
+pub(crate) const AUDIT_LOG: &str = "audit_log";
+
+/// Operation recorded in the audit log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum AuditOperation {
+    Save,
+    Fetch,
+    FetchHistory,
+    /// Recorded once per entry written to an export.
+    Export,
+    Delete,
+    DeleteAttribute,
+    /// Recorded with an empty key name.
+    DeleteNamespace,
+    Restore,
+}
+
+impl AuditOperation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::Save => "save",
+            AuditOperation::Fetch => "fetch",
+            AuditOperation::FetchHistory => "fetch_history",
+            AuditOperation::Export => "export",
+            AuditOperation::Delete => "delete",
+            AuditOperation::DeleteAttribute => "delete_attribute",
+            AuditOperation::DeleteNamespace => "delete_namespace",
+            AuditOperation::Restore => "restore",
+        }
+    }
+}
+
+/// One record of the audit log, as returned by [`Vault::audit_log`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditRecord {
+    pub id: i64,
+    /// `save`, `fetch`, `fetch_history`, `export`, `delete`, `delete_attribute`, `delete_namespace`
+    /// or `restore`.
+    pub operation: String,
+    pub namespace: String,
+    /// Empty for `delete_namespace`.
+    pub key_name: String,
+    /// Process that performed the operation.
+    pub pid: i64,
+    pub executable: Option<String>,
+    /// `ok`, `not_found`, `expired`, `conflict` or `error`.
+    pub outcome: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<AuditLog> for AuditRecord {
+    fn from(record: AuditLog) -> Self {
+        AuditRecord {
+            id: record.id,
+            operation: record.operation,
+            namespace: record.namespace,
+            key_name: record.key_name,
+            pid: record.pid,
+            executable: record.executable,
+            outcome: record.outcome,
+            created_at: record.created_at,
+        }
+    }
+}
+
+pub(crate) fn outcome<T>(result: &Result<T, CacheVaultError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(CacheVaultError::SqlxError(sqlx::Error::RowNotFound)) => "not_found",
+        Err(CacheVaultError::Expired { .. }) => "expired",
+        Err(CacheVaultError::VersionMismatch { .. }) => "conflict",
+        Err(_) => "error",
+    }
+}
+
+/// The key the audit log is hashed with, derived from the pepper so that the pepper itself is only
+/// used for attribute digests.
+fn audit_key(keys: &dyn KeyProvider) -> Result<Vec<u8>, CacheVaultError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&keys.pepper()?)
+        .map_err(|e| CacheVaultError::Unknown(format!("invalid pepper: {}", e)))?;
+    mac.update(b"cache-vault audit log v1");
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// HMAC of the record's fields and the hash of the record before it.
+fn chain_hash(key: &[u8], previous: &[u8], record: &AuditLog) -> Result<Vec<u8>, CacheVaultError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|e| CacheVaultError::Unknown(format!("invalid audit log key: {}", e)))?;
+    mac.update(previous);
+    mac.update(&record.id.to_le_bytes());
+    mac.update(&record.pid.to_le_bytes());
+    let created_at = record.created_at.format("%Y-%m-%d %H:%M:%S%.f").to_string();
+    let fields = [
+        Some(record.operation.as_str()),
+        Some(record.namespace.as_str()),
+        Some(record.key_name.as_str()),
+        record.executable.as_deref(),
+        Some(record.outcome.as_str()),
+        Some(created_at.as_str()),
+    ];
+    for field in fields {
+        // Length-prefixed so that moving bytes between fields changes the hash.
+        match field {
+            Some(field) => {
+                mac.update(&[1]);
+                mac.update(&(field.len() as u64).to_le_bytes());
+                mac.update(field.as_bytes());
+            }
+            None => mac.update(&[0]),
+        }
+    }
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Appends a record hashed with `key`, from [`Vault::audit_key`], through `conn`. `conn` must be
+/// inside a transaction so that no other record is appended between the insert and the read of
+/// the previous hash.
+pub(crate) async fn append(
+    conn: &mut SqliteConnection,
+    key: &[u8],
+    operation: AuditOperation,
+    namespace: &str,
+    key_name: &str,
+    outcome: &str,
+) -> Result<(), CacheVaultError> {
+    let executable = std::env::current_exe()
+        .ok()
+        .map(|path| path.to_string_lossy().into_owned());
+    // Inserting first takes the write lock before the previous hash is read.
+    let id = AuditLog::insert(
+        &mut *conn,
+        operation.as_str(),
+        namespace,
+        key_name,
+        std::process::id().into(),
+        executable.as_deref(),
+        outcome,
+        Utc::now().naive_utc(),
+    )
+    .await?;
+    let previous = AuditLog::previous_hash(&mut *conn, id)
+        .await?
+        .flatten()
+        .unwrap_or_default();
+    let record = AuditLog::fetch_by_id(&mut *conn, id).await?;
+    AuditLog::update_hash(&mut *conn, id, &chain_hash(key, &previous, &record)?).await
+}
+
+impl Vault {
+    /// The key for [`append`], read from `keys`, or `None` if the audit log is disabled. Callers
+    /// recording many operations read it once.
+    pub(crate) fn audit_key(&self, keys: &dyn KeyProvider) -> Result<Option<Vec<u8>>, CacheVaultError> {
+        if !self.audit_log_enabled() {
+            return Ok(None);
+        }
+        audit_key(keys).map(Some)
+    }
+
+    /// Records the outcome of `operation` through `conn`, as part of the caller's transaction.
+    pub(crate) async fn audit_in(
+        &self,
+        conn: &mut SqliteConnection,
+        operation: AuditOperation,
+        namespace: &str,
+        key_name: &str,
+        outcome: &str,
+    ) -> Result<(), CacheVaultError> {
+        match self.audit_key(self.keys())? {
+            Some(key) => append(conn, &key, operation, namespace, key_name, outcome).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Records the outcome of `operation` in a transaction of its own.
+    pub(crate) async fn audit(
+        &self,
+        operation: AuditOperation,
+        namespace: &str,
+        key_name: &str,
+        outcome: &str,
+    ) -> Result<(), CacheVaultError> {
+        if !self.audit_log_enabled() {
+            return Ok(());
+        }
+        let mut tx = self.pool().begin().await?;
+        self.audit_in(&mut tx, operation, namespace, key_name, outcome).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Records `operation` as `ok` for every `(namespace, key_name)` in `entries`, in one
+    /// transaction of its own.
+    pub(crate) async fn audit_all<'a>(
+        &self,
+        operation: AuditOperation,
+        entries: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<(), CacheVaultError> {
+        let Some(key) = self.audit_key(self.keys())? else {
+            return Ok(());
+        };
+        let mut tx = self.pool().begin().await?;
+        for (namespace, key_name) in entries {
+            append(&mut tx, &key, operation, namespace, key_name, "ok").await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// All records of the audit log, oldest first. See [`VaultBuilder::audit_log`](crate::VaultBuilder::audit_log).
+    pub async fn audit_log(&self) -> Result<Vec<AuditRecord>, CacheVaultError> {
+        let records = AuditLog::fetch_all(self.pool()).await?;
+        Ok(records.into_iter().map(AuditRecord::from).collect())
+    }
+
+    /// Checks every record of the audit log against its hash and the hash of the record before
+    /// it, returning how many records were verified.
+    ///
+    /// Fails with [`CacheVaultError::AuditLogTampered`] at the first record that was modified or
+    /// whose predecessors were removed. Removing the newest records cannot be told apart from them
+    /// never having been written, so compare the count with one obtained earlier to detect that.
+    pub async fn verify_audit_log(&self) -> Result<u64, CacheVaultError> {
+        let key = audit_key(self.keys())?;
+        let mut previous = Vec::new();
+        let mut count = 0;
+        for record in AuditLog::fetch_all(self.pool()).await? {
+            let expected = chain_hash(&key, &previous, &record)?;
+            if record.hash.as_ref() != Some(&expected) {
+                return Err(CacheVaultError::AuditLogTampered { id: record.id });
+            }
+            previous = expected;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::StaticKeyProvider;
+    use crate::plaintext::PlaintextFormat;
+    use crate::vault::tests::test_database_path;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_audit_log() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+        let keys = StaticKeyProvider::generate();
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(keys.clone())
+            .audit_log(true)
+            .open()
+            .await?;
+        vault.save("test", "token", "value", None, None).await?;
+        vault.fetch("test", "token").await?;
+        assert!(vault.fetch("test", "missing").await.is_err());
+        assert!(vault.delete("test", "token").await?);
+        assert!(!vault.delete("test", "token").await?);
+        let attributes = HashMap::from([(String::from("env"), String::from("prod"))]);
+        vault.save("other", "token", "value", Some(attributes), None).await?;
+        assert!(vault.delete_attribute("other", "token", "env").await?);
+        vault
+            .transaction(|tx| Box::pin(async move { tx.delete_attribute("other", "token", "env").await }))
+            .await?;
+        assert!(vault.delete_namespace("other").await?);
+
+        let records = vault.audit_log().await?;
+        let summary = records
+            .iter()
+            .map(|r| (r.operation.as_str(), r.key_name.as_str(), r.outcome.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("save", "token", "ok"),
+                ("fetch", "token", "ok"),
+                ("fetch", "missing", "not_found"),
+                ("delete", "token", "ok"),
+                ("delete", "token", "not_found"),
+                ("save", "token", "ok"),
+                ("delete_attribute", "token", "ok"),
+                ("delete_attribute", "token", "not_found"),
+                ("delete_namespace", "", "ok"),
+            ]
+        );
+        assert_eq!(records[0].pid, i64::from(std::process::id()));
+        assert_ne!(audit_key(&keys)?, keys.pepper()?);
+        assert_eq!(vault.verify_audit_log().await?, 9);
+        vault.pool().close().await;
+
+        // The setting is remembered.
+        let vault = Vault::builder().path(&path).key_provider(keys).open().await?;
+        vault.fetch_many("test", &["token"]).await?;
+        assert_eq!(vault.verify_audit_log().await?, 10);
+
+        sqlx::query("update audit_log set outcome = 'ok' where id = 3")
+            .execute(vault.pool())
+            .await?;
+        assert!(matches!(
+            vault.verify_audit_log().await,
+            Err(CacheVaultError::AuditLogTampered { id: 3 })
+        ));
+        sqlx::query("update audit_log set outcome = 'not_found' where id = 3")
+            .execute(vault.pool())
+            .await?;
+        sqlx::query("delete from audit_log where id = 2")
+            .execute(vault.pool())
+            .await?;
+        assert!(matches!(
+            vault.verify_audit_log().await,
+            Err(CacheVaultError::AuditLogTampered { id: 3 })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_reads() -> Result<(), CacheVaultError> {
+        let (path, dir) = test_database_path()?;
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(StaticKeyProvider::generate())
+            .audit_log(true)
+            .open()
+            .await?;
+        vault.set_history_limit("test", 1).await?;
+        vault.save("test", "token", "value1", None, None).await?;
+        vault.save("test", "token", "value2", None, None).await?;
+        vault.save("other", "token", "value", None, None).await?;
+        assert_eq!(vault.fetch_history("test", "token", 1).await?, "value1");
+        assert!(vault.fetch_history("test", "token", 9).await.is_err());
+        vault.export(dir.path().join("export"), "passphrase").await?;
+        vault
+            .export_plaintext(dir.path().join("export.json"), &PlaintextFormat::Json)
+            .await?;
+
+        let records = vault.audit_log().await?;
+        let summary = records
+            .iter()
+            .skip(3)
+            .map(|r| (r.operation.as_str(), r.namespace.as_str(), r.outcome.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("fetch_history", "test", "ok"),
+                ("fetch_history", "test", "not_found"),
+                ("export", "other", "ok"),
+                ("export", "test", "ok"),
+                ("export", "other", "ok"),
+                ("export", "test", "ok"),
+            ]
+        );
+        assert_eq!(vault.verify_audit_log().await?, 9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_failed_delete() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(StaticKeyProvider::generate())
+            .audit_log(true)
+            .open()
+            .await?;
+        vault.save("test", "token", "value", None, None).await?;
+        sqlx::query(
+            r#"
+              create trigger reject_delete before delete on entries
+              begin
+                select raise(abort, 'deleted');
+              end
+            "#,
+        )
+        .execute(vault.pool())
+        .await?;
+
+        assert!(vault.delete("test", "token").await.is_err());
+        assert_eq!(vault.fetch_including_expired("test", "token").await?.0, "value");
+        let outcomes = vault
+            .audit_log()
+            .await?
+            .into_iter()
+            .map(|record| (record.operation, record.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes[..2],
+            [
+                (String::from("save"), String::from("ok")),
+                (String::from("delete"), String::from("error")),
+            ]
+        );
+        assert_eq!(vault.verify_audit_log().await?, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_disabled() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = crate::vault::tests::open_test_vault().await?;
+        vault.save("test", "token", "value", None, None).await?;
+        vault.fetch("test", "token").await?;
+        assert!(vault.audit_log().await?.is_empty());
+        assert_eq!(vault.verify_audit_log().await?, 0);
+        Ok(())
+    }
+}
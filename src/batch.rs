@@ -0,0 +1,196 @@
+use chrono::NaiveDateTime;
+use std::collections::hash_map::Entry::Vacant;
+use std::collections::HashMap;
+
+use crate::audit::{append, AuditOperation};
+use crate::envelope::{namespace_keys_in, NamespaceKeys};
+use crate::error::CacheVaultError;
+use crate::history::history_limit_in;
+use crate::key::StaticKeyProvider;
+use crate::models::Entry;
+use crate::vault::{NewEntry, Vault};
+
+/// One entry for [`Vault::save_many`]: namespace, key name, value, attributes and expiry, as
+/// passed to [`Vault::save`].
+pub type BatchEntry<'a> = (
+    &'a str,
+    &'a str,
+    &'a str,
+    Option<HashMap<String, String>>,
+    Option<NaiveDateTime>,
+);
+
+impl Vault {
+    /// Saves all `entries` in one transaction, reading the master keys once.
+    ///
+    /// Either every entry is saved or, on error, none is.
+    pub async fn save_many(&self, entries: Vec<BatchEntry<'_>>) -> Result<(), CacheVaultError> {
+        let master = StaticKeyProvider::snapshot(self.keys())?;
+        let audit_key = self.audit_key(&master)?;
+        let mut keys: HashMap<&str, (NamespaceKeys, u32)> = HashMap::new();
+        let mut tx = self.pool().begin().await?;
+        for (namespace, ..) in entries.iter() {
+            if let Vacant(vacant) = keys.entry(namespace) {
+                let namespace_keys = namespace_keys_in(&mut tx, &master, namespace).await?;
+                let history_limit = history_limit_in(&mut *tx, namespace).await?;
+                vacant.insert((namespace_keys, history_limit));
+            }
+        }
+        for (namespace, key_name, value, attributes, expired_at) in entries {
+            let entry = NewEntry {
+                attributes,
+                expired_at,
+                ..NewEntry::new(namespace, key_name, value.as_bytes())
+            };
+            let (namespace_keys, history_limit) = &keys[namespace];
+            entry
+                .write(&mut tx, namespace_keys, self.algorithm(), *history_limit)
+                .await?;
+            if let Some(audit_key) = &audit_key {
+                append(&mut tx, audit_key, AuditOperation::Save, namespace, key_name, "ok").await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Fetches the values of `key_names` in `namespace` with a single query.
+    ///
+    /// Keys that do not exist or have expired are left out of the returned map.
+    pub async fn fetch_many(
+        &self,
+        namespace: &str,
+        key_names: &[&str],
+    ) -> Result<HashMap<String, (String, Option<NaiveDateTime>)>, CacheVaultError> {
+        let entries = Entry::fetch_many(self.pool(), namespace, key_names).await?;
+        if let Some(audit_key) = self.audit_key(self.keys())? {
+            let mut tx = self.pool().begin().await?;
+            for key_name in key_names {
+                let outcome = match entries.iter().find(|entry| entry.key_name == *key_name) {
+                    Some(entry) if entry.is_expired() => "expired",
+                    Some(_) => "ok",
+                    None => "not_found",
+                };
+                append(&mut tx, &audit_key, AuditOperation::Fetch, namespace, key_name, outcome).await?;
+            }
+            tx.commit().await?;
+        }
+        let keys = self.existing_namespace_keys(namespace).await?;
+        let mut values = HashMap::new();
+        for mut entry in entries.into_iter().filter(|entry| !entry.is_expired()) {
+            self.slide_expiration(&mut entry).await?;
+            let value = entry.plaintext(&keys)?;
+            values.insert(entry.key_name, (value, entry.expired_at));
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::KeyProvider;
+    use crate::vault::tests::{open_test_vault, test_database_path};
+    use chrono::{Duration, Utc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts how often the pepper is read.
+    struct CountingKeyProvider {
+        keys: StaticKeyProvider,
+        pepper_reads: Arc<AtomicUsize>,
+    }
+
+    impl KeyProvider for CountingKeyProvider {
+        fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError> {
+            self.keys.encryption_key()
+        }
+
+        fn pepper(&self) -> Result<Vec<u8>, CacheVaultError> {
+            self.pepper_reads.fetch_add(1, Ordering::SeqCst);
+            self.keys.pepper()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_many_and_fetch_many() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let past = Utc::now().naive_utc() - Duration::seconds(1);
+        let attributes = HashMap::from([(String::from("attr1"), String::from("attr1-value"))]);
+        vault
+            .save_many(vec![
+                ("test", "test-key1", "test-value1", Some(attributes.clone()), None),
+                ("test", "test-key2", "test-value2", None, None),
+                ("test", "expired-key", "stale-value", None, Some(past)),
+                ("other", "test-key1", "other-value1", None, None),
+            ])
+            .await?;
+
+        let values = vault
+            .fetch_many("test", &["test-key1", "test-key2", "expired-key", "no-such-key"])
+            .await?;
+        assert_eq!(
+            values,
+            HashMap::from([
+                (String::from("test-key1"), (String::from("test-value1"), None)),
+                (String::from("test-key2"), (String::from("test-value2"), None)),
+            ])
+        );
+        let (_, _, attrs) = vault.fetch_with_attributes("test", "test-key1").await?;
+        assert_eq!(attrs, Some(attributes));
+        assert_eq!(vault.fetch("other", "test-key1").await?.0, "other-value1");
+        assert!(vault.fetch_many("test", &[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_many_reads_keys_once() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+        let pepper_reads = Arc::new(AtomicUsize::new(0));
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(CountingKeyProvider {
+                keys: StaticKeyProvider::generate(),
+                pepper_reads: pepper_reads.clone(),
+            })
+            .audit_log(true)
+            .open()
+            .await?;
+        vault
+            .save_many(vec![
+                ("test", "test-key1", "test-value1", None, None),
+                ("test", "test-key2", "test-value2", None, None),
+                ("other", "test-key1", "other-value1", None, None),
+            ])
+            .await?;
+        assert_eq!(pepper_reads.load(Ordering::SeqCst), 1);
+        assert_eq!(vault.verify_audit_log().await?, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_many_is_atomic() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        // Make the second insert fail inside the transaction.
+        sqlx::query(
+            r#"
+              create trigger reject_bad_key before insert on entries
+              when new.key_name = 'bad'
+              begin
+                select raise(abort, 'bad key');
+              end
+            "#,
+        )
+        .execute(vault.pool())
+        .await?;
+        let result = vault
+            .save_many(vec![
+                ("test", "good", "value", None, None),
+                ("test", "bad", "value", None, None),
+            ])
+            .await;
+        assert!(result.is_err());
+        assert!(vault.fetch("test", "good").await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use chrono::{NaiveDateTime, TimeDelta};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::CacheVaultError;
+use crate::expiry::{expires_in, time_delta};
+use crate::vault::Vault;
+
+type KeyLock = Arc<tokio::sync::Mutex<()>>;
+
+/// Per-key locks held while a value is being computed, shared by clones of a [`Vault`].
+#[derive(Default)]
+pub(crate) struct InFlight(Mutex<HashMap<(String, String), KeyLock>>);
+
+impl InFlight {
+    fn lock_for(&self, namespace: &str, key_name: &str) -> InFlightKey<'_> {
+        let key = (namespace.to_string(), key_name.to_string());
+        let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let lock = locks.entry(key.clone()).or_default().clone();
+        InFlightKey {
+            in_flight: self,
+            key,
+            lock,
+        }
+    }
+}
+
+/// A caller's reference to the lock for one key. Dropping it, also when the caller's future is
+/// cancelled, removes the lock from the map unless another caller is still waiting on it.
+struct InFlightKey<'a> {
+    in_flight: &'a InFlight,
+    key: (String, String),
+    lock: KeyLock,
+}
+
+impl Drop for InFlightKey<'_> {
+    fn drop(&mut self) {
+        let mut locks = self.in_flight.0.lock().unwrap_or_else(|e| e.into_inner());
+        // One reference is held by the map and one by `self.lock`.
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+fn is_miss(result: &Result<(String, Option<NaiveDateTime>), CacheVaultError>) -> bool {
+    matches!(
+        result,
+        Err(CacheVaultError::SqlxError(sqlx::Error::RowNotFound)) | Err(CacheVaultError::Expired { .. })
+    )
+}
+
+impl Vault {
+    /// Returns the cached value of `key_name`, or computes it with `f` and saves it with its
+    /// attributes to expire `ttl` from now.
+    ///
+    /// Concurrent callers for the same key through this vault (or its clones) wait for a single
+    /// computation instead of each running `f`. An error from `f` is returned as is and nothing is
+    /// saved. A `ttl` too large to expire by is rejected before `f` runs.
+    pub async fn get_or_insert_with<F, Fut, E>(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        ttl: Duration,
+        f: F,
+    ) -> Result<String, CacheVaultError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(String, Option<HashMap<String, String>>), E>>,
+        CacheVaultError: From<E>,
+    {
+        let ttl = time_delta(ttl)?;
+        expires_in(ttl)?;
+        let result = self.fetch(namespace, key_name).await;
+        if !is_miss(&result) {
+            return Ok(result?.0);
+        }
+
+        let in_flight_key = self.in_flight().lock_for(namespace, key_name);
+        let _guard = in_flight_key.lock.lock().await;
+        self.compute_on_miss(namespace, key_name, ttl, f).await
+    }
+
+    async fn compute_on_miss<F, Fut, E>(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        ttl: TimeDelta,
+        f: F,
+    ) -> Result<String, CacheVaultError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(String, Option<HashMap<String, String>>), E>>,
+        CacheVaultError: From<E>,
+    {
+        // Another caller may have saved the value while this one was waiting.
+        let result = self.fetch(namespace, key_name).await;
+        if !is_miss(&result) {
+            return Ok(result?.0);
+        }
+        let (value, attributes) = f().await?;
+        let expired_at = expires_in(ttl)?;
+        self.save(namespace, key_name, &value, attributes, Some(expired_at))
+            .await?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::tests::open_test_vault;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_get_or_insert_with() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let compute = || {
+            let calls = calls.clone();
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let attributes = HashMap::from([(String::from("issuer"), String::from("sts"))]);
+                Ok::<_, CacheVaultError>((String::from("token"), Some(attributes)))
+            }
+        };
+
+        let ttl = Duration::from_secs(3600);
+        let results = futures::future::join_all((0..4).map(|_| {
+            let vault = vault.clone();
+            async move { vault.get_or_insert_with("test", "token", ttl, compute).await }
+        }))
+        .await;
+        for result in results {
+            assert_eq!(result?, "token");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(vault.in_flight().0.lock().unwrap().is_empty());
+
+        let (value, expired_at, attributes) = vault.fetch_with_attributes("test", "token").await?;
+        assert_eq!(value, "token");
+        assert!(expired_at.is_some());
+        assert_eq!(attributes.unwrap()["issuer"], "sts");
+
+        assert_eq!(vault.get_or_insert_with("test", "token", ttl, compute).await?, "token");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_or_insert_with_error() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let result = vault
+            .get_or_insert_with("test", "token", Duration::from_secs(60), || async {
+                Err::<(String, Option<HashMap<String, String>>), _>(CacheVaultError::Unknown(String::from("boom")))
+            })
+            .await;
+        assert!(matches!(result, Err(CacheVaultError::Unknown(_))));
+        assert!(vault.fetch("test", "token").await.is_err());
+
+        let calls = AtomicUsize::new(0);
+        let result = vault
+            .get_or_insert_with("test", "token", Duration::from_secs(10_000_000_000_000), || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, CacheVaultError>((String::from("token"), None))
+            })
+            .await;
+        assert!(matches!(result, Err(CacheVaultError::InvalidDuration(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_or_insert_with_cancelled() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let result = tokio::time::timeout(
+            Duration::from_millis(10),
+            vault.get_or_insert_with("test", "token", Duration::from_secs(60), || async {
+                std::future::pending::<Result<(String, Option<HashMap<String, String>>), CacheVaultError>>().await
+            }),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(vault.in_flight().0.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
@@ -1,61 +1,43 @@
 use sqlx::migrate::Migrator;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
-use std::sync::LazyLock;
-use std::sync::OnceLock;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::path::{Path, PathBuf};
 
 use crate::error::CacheVaultError;
 
-#[allow(dead_code)]
 static MIGRATOR: Migrator = sqlx::migrate!();
 
-pub static POOL: LazyLock<SqlitePool> = LazyLock::new(|| {
-    let options = SqliteConnectOptions::new().filename(&*DB_PATH).create_if_missing(true);
-    let conn = SqlitePool::connect_lazy_with(options);
-    conn
-});
-
-#[cfg(not(test))]
-static DB_PATH: LazyLock<String> = LazyLock::new(|| {
-    let config_dir = dirs::config_dir().expect("Unable to get default config directory");
-    let default_db_path = config_dir
-        .join("cache-vault/cache-vault.db")
-        .to_str()
-        .unwrap()
-        .to_string();
-    let db_path = std::env::var("CACHE_VAULT_DATABASE_PATH").unwrap_or(default_db_path);
-    db_path
-});
-
-#[cfg(test)]
-static DB_PATH: LazyLock<String> = LazyLock::new(|| {
-    use tempfile::NamedTempFile;
-    let file = NamedTempFile::new().unwrap();
-    let path = String::from(file.path().to_string_lossy());
-    let _ = file.close();
-    path
-});
-
-#[allow(dead_code)]
-static MIGRATED: OnceLock<bool> = OnceLock::new();
+pub fn default_path() -> Result<PathBuf, CacheVaultError> {
+    if let Ok(path) = std::env::var("CACHE_VAULT_DATABASE_PATH") {
+        return Ok(PathBuf::from(path));
+    }
+    let config_dir = dirs::config_dir().ok_or(CacheVaultError::DefaultPath)?;
+    Ok(config_dir.join("cache-vault/cache-vault.db"))
+}
 
-#[allow(dead_code)]
-pub async fn migrate() -> Result<(), CacheVaultError> {
-    if MIGRATED.get().is_none() {
-        dbg!("migrate");
-        MIGRATOR.run(&*POOL).await.map_err(CacheVaultError::MigrateError)?;
-        let _ = MIGRATED.set(true);
+pub async fn connect(path: &Path, pool_options: SqlitePoolOptions) -> Result<SqlitePool, CacheVaultError> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(CacheVaultError::Io)?;
     }
-    Ok(())
+    let options = SqliteConnectOptions::new().filename(path).create_if_missing(true);
+    let pool = pool_options.connect_with(options).await?;
+    Ok(pool)
+}
+
+pub async fn migrate(pool: &SqlitePool) -> Result<(), CacheVaultError> {
+    MIGRATOR.run(pool).await.map_err(CacheVaultError::MigrateError)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile::NamedTempFile;
 
     #[tokio::test]
     async fn test_database() -> Result<(), CacheVaultError> {
-        migrate().await?;
-        let _ = sqlx::query(r#"select 1 as id"#).fetch_one(&*POOL).await?;
+        let file = NamedTempFile::new().unwrap();
+        let pool = connect(file.path(), SqlitePoolOptions::new()).await?;
+        migrate(&pool).await?;
+        let _ = sqlx::query(r#"select 1 as id"#).fetch_one(&pool).await?;
         Ok(())
     }
 }
@@ -1,42 +1,187 @@
+use crate::base32::encode;
 use crate::error::CacheVaultError;
 
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::aead::generic_array::typenum::Unsigned;
 use chacha20poly1305::aead::generic_array::GenericArray;
 use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
-use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use std::fmt;
+use std::str::FromStr;
 
-use crate::key::Key;
+/// AEAD used to encrypt values. Stored per row, so rows written with any algorithm stay readable.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Algorithm {
+    /// ChaCha20-Poly1305 with random 96-bit nonces.
+    #[default]
+    ChaCha20Poly1305,
+    /// XChaCha20-Poly1305 with random 192-bit nonces, safe for very many writes under one key.
+    XChaCha20Poly1305,
+    /// AES-256-GCM-SIV, which degrades gracefully if a nonce is ever repeated.
+    Aes256GcmSiv,
+}
+
+impl Algorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::ChaCha20Poly1305 => "chacha20-poly1305",
+            Algorithm::XChaCha20Poly1305 => "xchacha20-poly1305",
+            Algorithm::Aes256GcmSiv => "aes-256-gcm-siv",
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = CacheVaultError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chacha20-poly1305" => Ok(Algorithm::ChaCha20Poly1305),
+            "xchacha20-poly1305" => Ok(Algorithm::XChaCha20Poly1305),
+            "aes-256-gcm-siv" => Ok(Algorithm::Aes256GcmSiv),
+            _ => Err(CacheVaultError::UnknownAlgorithm(s.to_string())),
+        }
+    }
+}
+
+fn cipher<C: KeyInit>(key: &[u8]) -> Result<C, CacheVaultError> {
+    C::new_from_slice(key).map_err(|_| CacheVaultError::InvalidKeyLength(key.len()))
+}
 
-pub fn encrypt(raw: String) -> Result<(Vec<u8>, Vec<u8>), CacheVaultError> {
-    let key = Key::default().get()?;
-    let key = GenericArray::from_slice(&key);
-    let cipher = ChaCha20Poly1305::new(&key);
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng); // 96-bits; unique per message
-    let plaintext = Payload::from(raw.as_bytes());
+fn seal<C: KeyInit + Aead>(key: &[u8], aad: &[u8], raw: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CacheVaultError> {
+    let cipher = cipher::<C>(key)?;
+    let nonce = C::generate_nonce(&mut OsRng); // random; unique per message
+    let plaintext = Payload { msg: raw, aad };
     let ciphertext = cipher.encrypt(&nonce, plaintext).map_err(CacheVaultError::ChaCha20)?;
     Ok((ciphertext, nonce.to_vec()))
 }
 
-pub fn decrypt(nonce: &Vec<u8>, encrypted: &Vec<u8>) -> Result<String, CacheVaultError> {
-    let key = Key::default().get()?;
-    let key = GenericArray::from_slice(&key);
-    let cipher = ChaCha20Poly1305::new(&key);
-    let ciphertext = Payload::from(encrypted.as_ref());
-    let nonce = GenericArray::from_slice(nonce.as_ref());
-    let plaintext = cipher.decrypt(&nonce, ciphertext).map_err(CacheVaultError::ChaCha20)?;
+fn open<C: KeyInit + Aead>(key: &[u8], aad: &[u8], nonce: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, CacheVaultError> {
+    let cipher = cipher::<C>(key)?;
+    let ciphertext = Payload { msg: encrypted, aad };
+    if nonce.len() != <C as AeadCore>::NonceSize::USIZE {
+        return Err(CacheVaultError::ChaCha20(chacha20poly1305::Error));
+    }
+    let nonce = GenericArray::from_slice(nonce);
+    cipher.decrypt(nonce, ciphertext).map_err(CacheVaultError::ChaCha20)
+}
+
+/// Identifies `key` without revealing it: the tag of an empty message under an all-zero nonce.
+pub fn key_id(key: &[u8]) -> Result<String, CacheVaultError> {
+    let tag = cipher::<ChaCha20Poly1305>(key)?
+        .encrypt(GenericArray::from_slice(&[0u8; 12]), b"".as_ref())
+        .map_err(CacheVaultError::ChaCha20)?;
+    Ok(encode(&tag[..8]))
+}
+
+/// Builds unambiguous associated data from length-prefixed `parts`.
+pub fn associated_data(parts: &[&[u8]]) -> Vec<u8> {
+    let mut aad = Vec::new();
+    for part in parts {
+        aad.extend_from_slice(&(part.len() as u64).to_le_bytes());
+        aad.extend_from_slice(part);
+    }
+    aad
+}
+
+pub fn encrypt(
+    algorithm: Algorithm,
+    key: &[u8],
+    aad: &[u8],
+    raw: String,
+) -> Result<(Vec<u8>, Vec<u8>), CacheVaultError> {
+    encrypt_bytes(algorithm, key, aad, raw.as_bytes())
+}
+
+pub fn decrypt(
+    algorithm: Algorithm,
+    key: &[u8],
+    aad: &[u8],
+    nonce: &[u8],
+    encrypted: &[u8],
+) -> Result<String, CacheVaultError> {
+    let plaintext = decrypt_bytes(algorithm, key, aad, nonce, encrypted)?;
     String::from_utf8(plaintext).map_err(CacheVaultError::FromUtf8Error)
 }
 
+pub fn encrypt_bytes(
+    algorithm: Algorithm,
+    key: &[u8],
+    aad: &[u8],
+    raw: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), CacheVaultError> {
+    match algorithm {
+        Algorithm::ChaCha20Poly1305 => seal::<ChaCha20Poly1305>(key, aad, raw),
+        Algorithm::XChaCha20Poly1305 => seal::<XChaCha20Poly1305>(key, aad, raw),
+        Algorithm::Aes256GcmSiv => seal::<Aes256GcmSiv>(key, aad, raw),
+    }
+}
+
+pub fn decrypt_bytes(
+    algorithm: Algorithm,
+    key: &[u8],
+    aad: &[u8],
+    nonce: &[u8],
+    encrypted: &[u8],
+) -> Result<Vec<u8>, CacheVaultError> {
+    match algorithm {
+        Algorithm::ChaCha20Poly1305 => open::<ChaCha20Poly1305>(key, aad, nonce, encrypted),
+        Algorithm::XChaCha20Poly1305 => open::<XChaCha20Poly1305>(key, aad, nonce, encrypted),
+        Algorithm::Aes256GcmSiv => open::<Aes256GcmSiv>(key, aad, nonce, encrypted),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::key::{KeyProvider, StaticKeyProvider};
     use anyhow::{Context, Result};
 
     #[test]
     fn test_encrypt_decrypt() -> Result<()> {
+        let key = StaticKeyProvider::generate().encryption_key()?;
         let plaintext = String::from("Hello, Rust");
-        let (encrypted, nonce) = encrypt(plaintext.clone()).context("encrypt error")?;
-        let decrypted = decrypt(&nonce, &encrypted).context("decrypt error")?;
-        assert_eq!(plaintext, decrypted);
+        for algorithm in [
+            Algorithm::ChaCha20Poly1305,
+            Algorithm::XChaCha20Poly1305,
+            Algorithm::Aes256GcmSiv,
+        ] {
+            let (encrypted, nonce) = encrypt(algorithm, &key, b"aad", plaintext.clone()).context("encrypt error")?;
+            let decrypted = decrypt(algorithm, &key, b"aad", &nonce, &encrypted).context("decrypt error")?;
+            assert_eq!(plaintext, decrypted);
+            assert!(decrypt(algorithm, &key, b"other", &nonce, &encrypted).is_err());
+            assert_eq!(algorithm.as_str().parse::<Algorithm>()?, algorithm);
+        }
+        let (_, nonce) = encrypt(Algorithm::XChaCha20Poly1305, &key, b"", plaintext.clone())?;
+        assert_eq!(nonce.len(), 24);
         Ok(())
     }
+
+    #[test]
+    fn test_associated_data() {
+        assert_ne!(associated_data(&[b"ab", b"c"]), associated_data(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn test_key_id() -> Result<()> {
+        let keys = StaticKeyProvider::generate();
+        let key = keys.encryption_key()?;
+        assert_eq!(key_id(&key)?, key_id(&key)?);
+        assert_ne!(key_id(&key)?, key_id(&keys.pepper()?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_key_length() {
+        match encrypt(Algorithm::default(), b"too-short", b"", String::from("Hello, Rust")) {
+            Err(CacheVaultError::InvalidKeyLength(9)) => (),
+            _ => panic!("unexpected"),
+        }
+    }
 }
@@ -1,27 +1,33 @@
 use argon2::Argon2;
 
 use crate::error::CacheVaultError;
-use crate::key::Key;
 
-pub fn digest(data: &[u8]) -> Result<[u8; 32], CacheVaultError> {
-    let pepper = Key::pepper().get()?;
+pub fn digest(pepper: &[u8], data: &[u8]) -> Result<[u8; 32], CacheVaultError> {
     let mut output = [0u8; 32];
-    let _ = Argon2::default().hash_password_into(data, &pepper, &mut output);
+    Argon2::default().hash_password_into(data, pepper, &mut output)?;
     Ok(output)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::key::{KeyProvider, StaticKeyProvider};
 
     #[test]
     fn test_digest() -> Result<(), CacheVaultError> {
-        let v1 = digest(b"secret-password")?;
-        let v2 = digest(b"secret-password")?;
-        let v3 = digest(b"secret-password2")?;
+        let pepper = StaticKeyProvider::generate().pepper()?;
+        let v1 = digest(&pepper, b"secret-password")?;
+        let v2 = digest(&pepper, b"secret-password")?;
+        let v3 = digest(&pepper, b"secret-password2")?;
 
         assert_eq!(v1, v2);
         assert_ne!(v1, v3);
+
+        // Argon2 refuses salts shorter than 8 bytes.
+        assert!(matches!(
+            digest(b"pep", b"secret-password"),
+            Err(CacheVaultError::Argon2(_))
+        ));
         Ok(())
     }
 }
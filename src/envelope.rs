@@ -0,0 +1,138 @@
+use sqlx::{SqliteConnection, SqliteExecutor};
+
+use crate::error::CacheVaultError;
+use crate::key::KeyProvider;
+use crate::models::NamespaceKey;
+use crate::vault::Vault;
+
+/// Keys for the rows of one namespace.
+///
+/// Rows are encrypted with the namespace's data encryption key; the master keys remain available
+/// for decrypting rows written before the namespace had one.
+pub(crate) struct NamespaceKeys {
+    data_key: Option<Vec<u8>>,
+    master_keys: Vec<Vec<u8>>,
+    pepper: Vec<u8>,
+}
+
+impl NamespaceKeys {
+    fn new(data_key: Option<Vec<u8>>, master: &dyn KeyProvider) -> Result<Self, CacheVaultError> {
+        let mut master_keys = vec![master.encryption_key()?];
+        master_keys.extend(master.decryption_keys()?);
+        Ok(Self {
+            data_key,
+            master_keys,
+            pepper: master.pepper()?,
+        })
+    }
+}
+
+impl KeyProvider for NamespaceKeys {
+    fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Ok(self.data_key.as_ref().unwrap_or(&self.master_keys[0]).clone())
+    }
+
+    fn pepper(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Ok(self.pepper.clone())
+    }
+
+    fn decryption_keys(&self) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+        match self.data_key {
+            Some(_) => Ok(self.master_keys.clone()),
+            None => Ok(self.master_keys[1..].to_vec()),
+        }
+    }
+}
+
+/// Keys for writing to `namespace` through `conn`, generating its data encryption key on first use.
+///
+/// The key is inserted unless one exists and then read back, so inside a transaction this takes
+/// the write lock at once and the key is only kept if the transaction commits.
+pub(crate) async fn namespace_keys_in(
+    conn: &mut SqliteConnection,
+    master: &dyn KeyProvider,
+    namespace: &str,
+) -> Result<NamespaceKeys, CacheVaultError> {
+    NamespaceKey::insert(&mut *conn, master, namespace).await?;
+    let namespace_key = NamespaceKey::fetch_optional(&mut *conn, namespace)
+        .await?
+        .ok_or(CacheVaultError::SqlxError(sqlx::Error::RowNotFound))?;
+    NamespaceKeys::new(Some(namespace_key.data_key(master)?), master)
+}
+
+/// Keys for reading from `namespace` through `executor`, without generating a data encryption key.
+pub(crate) async fn existing_namespace_keys_in<'e, E>(
+    executor: E,
+    master: &dyn KeyProvider,
+    namespace: &str,
+) -> Result<NamespaceKeys, CacheVaultError>
+where
+    E: SqliteExecutor<'e>,
+{
+    let data_key = match NamespaceKey::fetch_optional(executor, namespace).await? {
+        Some(namespace_key) => Some(namespace_key.data_key(master)?),
+        None => None,
+    };
+    NamespaceKeys::new(data_key, master)
+}
+
+impl Vault {
+    /// Keys for writing to `namespace`, generating its data encryption key on first use.
+    pub(crate) async fn namespace_keys(&self, namespace: &str) -> Result<NamespaceKeys, CacheVaultError> {
+        let mut conn = self.pool().acquire().await?;
+        namespace_keys_in(&mut conn, self.keys(), namespace).await
+    }
+
+    /// Keys for reading from `namespace`, without generating a data encryption key.
+    pub(crate) async fn existing_namespace_keys(&self, namespace: &str) -> Result<NamespaceKeys, CacheVaultError> {
+        existing_namespace_keys_in(self.pool(), self.keys(), namespace).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Entry;
+    use crate::vault::tests::open_test_vault;
+
+    #[tokio::test]
+    async fn test_namespace_keys() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault.save("test1", "test-key", "test-value1", None, None).await?;
+        vault.save("test2", "test-key", "test-value2", None, None).await?;
+
+        let namespace_key1 = NamespaceKey::fetch_optional(vault.pool(), "test1").await?.unwrap();
+        let namespace_key2 = NamespaceKey::fetch_optional(vault.pool(), "test2").await?.unwrap();
+        assert_ne!(namespace_key1.data_key_id, namespace_key2.data_key_id);
+        let entry = Entry::fetch(vault.pool(), "test1", "test-key").await?;
+        assert_eq!(entry.key_id, Some(namespace_key1.data_key_id));
+
+        // Dropping the data key makes the namespace unreadable.
+        sqlx::query(r#"delete from namespace_keys where namespace = "test1""#)
+            .execute(vault.pool())
+            .await?;
+        assert!(vault.fetch("test1", "test-key").await.is_err());
+        assert_eq!(vault.fetch("test2", "test-key").await?.0, "test-value2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_namespace_key_rolled_back_with_save() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        assert!(vault
+            .save_if_version("test", "test-key", 3, "test-value", None, None)
+            .await
+            .is_err());
+        assert!(NamespaceKey::fetch_optional(vault.pool(), "test").await?.is_none());
+
+        let (result1, result2) = tokio::join!(
+            vault.save("test", "test-key1", "test-value1", None, None),
+            vault.save("test", "test-key2", "test-value2", None, None)
+        );
+        result1?;
+        result2?;
+        assert_eq!(vault.fetch("test", "test-key1").await?.0, "test-value1");
+        assert_eq!(vault.fetch("test", "test-key2").await?.0, "test-value2");
+        Ok(())
+    }
+}
@@ -5,6 +5,39 @@ pub enum CacheVaultError {
     #[error("keyring error")]
     Keyring(#[from] keyring::Error),
 
+    #[error("environment variable {0} is not set")]
+    MissingEnv(String),
+
+    #[error("key file {0:?} must not be accessible by group or others")]
+    InsecureKeyFile(std::path::PathBuf),
+
+    #[error("invalid key length: {0}")]
+    InvalidKeyLength(usize),
+
+    #[error("invalid pepper length: {0}, expected 32")]
+    InvalidPepperLength(usize),
+
+    #[error("vault is locked")]
+    Locked,
+
+    #[error("invalid passphrase")]
+    InvalidPassphrase,
+
+    #[error("vault is passphrase protected, open it with VaultBuilder::passphrase_protected")]
+    PassphraseProtected,
+
+    #[error("vault is not passphrase protected")]
+    NotPassphraseProtected,
+
+    #[error("argon2 error")]
+    Argon2(#[from] argon2::Error),
+
+    #[error("no encryption key with id {0:?}")]
+    UnknownKeyId(String),
+
+    #[error("unknown encryption algorithm {0:?}")]
+    UnknownAlgorithm(String),
+
     #[error("crypt error")]
     ChaCha20(#[from] chacha20poly1305::Error),
 
@@ -17,6 +50,61 @@ pub enum CacheVaultError {
     #[error("migrate error")]
     MigrateError(#[from] sqlx::migrate::MigrateError),
 
+    #[error("unable to determine default database path")]
+    DefaultPath,
+
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+
+    #[error("{namespace:?}/{key_name:?} has expired")]
+    Expired { namespace: String, key_name: String },
+
+    #[error("value of {namespace:?}/{key_name:?} is not valid UTF-8, fetch it as bytes instead")]
+    NonUtf8Value { namespace: String, key_name: String },
+
+    #[error("value of {namespace:?}/{key_name:?} has content type {found:?}, expected {expected:?}")]
+    ContentTypeMismatch {
+        namespace: String,
+        key_name: String,
+        expected: String,
+        found: Option<String>,
+    },
+
+    #[error("{namespace:?}/{key_name:?} is at version {actual:?}, expected {expected}")]
+    VersionMismatch {
+        namespace: String,
+        key_name: String,
+        expected: i64,
+        actual: Option<i64>,
+    },
+
+    #[error("json error")]
+    Json(#[from] serde_json::Error),
+
+    #[error("yaml error")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("messagepack encode error")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+
+    #[error("messagepack decode error")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+
+    #[error("{namespace:?}/{key_name:?} already exists")]
+    ImportConflict { namespace: String, key_name: String },
+
+    #[error("invalid document: {0}")]
+    InvalidDocument(String),
+
+    #[error("invalid duration: {0}")]
+    InvalidDuration(String),
+
+    #[error("audit log record {id} was modified, or records before it were removed")]
+    AuditLogTampered { id: i64 },
+
     #[error("convert bytes to utf8 string error")]
     FromUtf8Error(#[from] std::string::FromUtf8Error),
 
@@ -0,0 +1,238 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::error::CacheVaultError;
+use crate::models::Entry;
+use crate::vault::{NewEntry, Vault};
+
+impl Vault {
+    /// Deletes every entry whose `expired_at` has passed, together with its attributes. Returns the
+    /// number of entries deleted.
+    pub async fn purge_expired(&self) -> Result<u64, CacheVaultError> {
+        Entry::delete_expired(self.pool(), Utc::now().naive_utc()).await
+    }
+
+    /// Saves `value` to expire `ttl` from now.
+    pub async fn save_with_ttl(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(), CacheVaultError> {
+        let expired_at = expires_in(time_delta(ttl)?)?;
+        self.save(namespace, key_name, value, None, Some(expired_at)).await
+    }
+
+    /// Like [`Vault::save_with_ttl`] but every successful fetch pushes the expiration `ttl` further
+    /// into the future, so the entry only expires after `ttl` without being read.
+    pub async fn save_with_sliding_ttl(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        value: &str,
+        ttl: Duration,
+    ) -> Result<(), CacheVaultError> {
+        let ttl = time_delta(ttl)?;
+        let expired_at = expires_in(ttl)?;
+        self.save_entry(NewEntry {
+            expired_at: Some(expired_at),
+            sliding_ttl_millis: Some(ttl.num_milliseconds()),
+            ..NewEntry::new(namespace, key_name, value.as_bytes())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Spawns a task on the current tokio runtime that calls [`Vault::purge_expired`] every
+    /// `period`, starting immediately.
+    ///
+    /// A failed purge is retried on the next tick. The task stops when the returned [`Sweeper`] is
+    /// shut down or dropped. `period` must not be zero.
+    pub fn spawn_sweeper(&self, period: Duration) -> Result<Sweeper, CacheVaultError> {
+        if period.is_zero() {
+            return Err(CacheVaultError::InvalidDuration(String::from(
+                "sweeper period must be greater than zero",
+            )));
+        }
+        let vault = self.clone();
+        let (shutdown, mut stop) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let mut ticks = interval(period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = &mut stop => return,
+                    _ = ticks.tick() => {
+                        let _ = vault.purge_expired().await;
+                    }
+                }
+            }
+        });
+        Ok(Sweeper {
+            shutdown: Some(shutdown),
+            handle,
+        })
+    }
+}
+
+pub(crate) fn time_delta(ttl: Duration) -> Result<TimeDelta, CacheVaultError> {
+    TimeDelta::from_std(ttl).map_err(|_| CacheVaultError::InvalidDuration(format!("ttl {:?} is out of range", ttl)))
+}
+
+/// Returns the time `ttl` from now, or [`CacheVaultError::InvalidDuration`] if that is past the
+/// latest representable time.
+pub(crate) fn expires_in(ttl: TimeDelta) -> Result<NaiveDateTime, CacheVaultError> {
+    Utc::now()
+        .naive_utc()
+        .checked_add_signed(ttl)
+        .ok_or_else(|| CacheVaultError::InvalidDuration(format!("ttl {} is too far in the future", ttl)))
+}
+
+/// Handle to the background task started by [`Vault::spawn_sweeper`].
+pub struct Sweeper {
+    shutdown: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<()>,
+}
+
+impl Sweeper {
+    /// Stops the sweeper, waiting for a purge in progress to finish.
+    pub async fn shutdown(mut self) -> Result<(), CacheVaultError> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        (&mut self.handle)
+            .await
+            .map_err(|e| CacheVaultError::Unknown(format!("sweeper task failed: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::tests::open_test_vault;
+    use chrono::Duration as ChronoDuration;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_purge_expired() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let past = Utc::now().naive_utc() - ChronoDuration::seconds(1);
+        let attributes = HashMap::from([(String::from("attr1"), String::from("attr1-value"))]);
+        vault
+            .save(
+                "test",
+                "expired-key",
+                "stale-value",
+                Some(attributes.clone()),
+                Some(past),
+            )
+            .await?;
+        vault
+            .save("test", "fresh-key", "fresh-value", Some(attributes), None)
+            .await?;
+
+        assert_eq!(vault.purge_expired().await?, 1);
+        assert_eq!(vault.purge_expired().await?, 0);
+        match vault.fetch_including_expired("test", "expired-key").await {
+            Err(CacheVaultError::SqlxError(sqlx::Error::RowNotFound)) => (),
+            _ => panic!("unexpected"),
+        }
+        let (count,): (i64,) = sqlx::query_as("select count(*) from attributes")
+            .fetch_one(vault.pool())
+            .await?;
+        assert_eq!(count, 1);
+        assert_eq!(vault.fetch("test", "fresh-key").await?.0, "fresh-value");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_with_ttl() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault
+            .save_with_ttl("test", "ttl-key", "ttl-value", Duration::from_secs(3600))
+            .await?;
+        let (value, expired_at) = vault.fetch("test", "ttl-key").await?;
+        assert_eq!(value, "ttl-value");
+        let remaining = expired_at.unwrap() - Utc::now().naive_utc();
+        assert!(remaining > ChronoDuration::minutes(59) && remaining <= ChronoDuration::hours(1));
+
+        vault
+            .save_with_ttl("test", "gone-key", "gone-value", Duration::ZERO)
+            .await?;
+        assert!(matches!(
+            vault.fetch("test", "gone-key").await,
+            Err(CacheVaultError::Expired { .. })
+        ));
+
+        let ttl = Duration::from_secs(10_000_000_000_000);
+        assert!(matches!(
+            vault.save_with_ttl("test", "far-key", "far-value", ttl).await,
+            Err(CacheVaultError::InvalidDuration(_))
+        ));
+        assert!(matches!(
+            vault.save_with_sliding_ttl("test", "far-key", "far-value", ttl).await,
+            Err(CacheVaultError::InvalidDuration(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sliding_ttl() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault
+            .save_with_sliding_ttl("test", "sliding-key", "sliding-value", Duration::from_secs(3600))
+            .await?;
+        let entry = Entry::fetch(vault.pool(), "test", "sliding-key").await?;
+
+        // As if the entry had not been read for almost an hour.
+        let soon = Utc::now().naive_utc() + ChronoDuration::seconds(1);
+        Entry::extend_expiration(vault.pool(), entry.id, entry.version, soon).await?;
+        let fetched_at = Utc::now().naive_utc();
+        let (value, expired_at) = vault.fetch("test", "sliding-key").await?;
+        assert_eq!(value, "sliding-value");
+        assert!(expired_at.unwrap() >= fetched_at + ChronoDuration::hours(1));
+        let entry = Entry::fetch(vault.pool(), "test", "sliding-key").await?;
+        assert_eq!(entry.expired_at, expired_at);
+
+        // An expiration that has passed is not extended.
+        let past = Utc::now().naive_utc() - ChronoDuration::seconds(1);
+        Entry::extend_expiration(vault.pool(), entry.id, entry.version, past).await?;
+        assert!(matches!(
+            vault.fetch("test", "sliding-key").await,
+            Err(CacheVaultError::Expired { .. })
+        ));
+
+        // A plain save replaces the sliding expiration, also for a fetch that read the entry before
+        // the save.
+        let mut stale = Entry::fetch(vault.pool(), "test", "sliding-key").await?;
+        vault.save("test", "sliding-key", "fixed-value", None, None).await?;
+        vault.slide_expiration(&mut stale).await?;
+        let entry = Entry::fetch(vault.pool(), "test", "sliding-key").await?;
+        assert_eq!(entry.sliding_ttl_millis, None);
+        assert_eq!(entry.expired_at, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sweeper() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let past = Utc::now().naive_utc() - ChronoDuration::seconds(1);
+        vault
+            .save("test", "expired-key", "stale-value", None, Some(past))
+            .await?;
+
+        assert!(matches!(
+            vault.spawn_sweeper(Duration::ZERO),
+            Err(CacheVaultError::InvalidDuration(_))
+        ));
+        let sweeper = vault.spawn_sweeper(Duration::from_millis(10))?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sweeper.shutdown().await?;
+        assert!(vault.fetch_including_expired("test", "expired-key").await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+use argon2::Params;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry::Vacant;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::audit::{append, AuditOperation};
+use crate::envelope::{existing_namespace_keys_in, namespace_keys_in, NamespaceKeys};
+use crate::error::CacheVaultError;
+use crate::history::{history_limit_in, set_history_limit_in};
+use crate::key::StaticKeyProvider;
+use crate::models::{Attribute, Entry};
+use crate::passphrase::derive;
+use crate::vault::{AttributesMode, NewEntry, Vault};
+
+const MAGIC: &[u8; 8] = b"CVEXPORT";
+const ARCHIVE_VERSION: u32 = 1;
+// Magic, version, salt, Argon2 m/t/p costs and nonce.
+const HEADER_LEN: usize = 8 + 4 + 16 + 4 * 3 + 12;
+// The costs are read before the archive is authenticated, so they are capped to keep a crafted
+// archive from exhausting memory or time. Memory is in KiB.
+const MAX_M_COST: u32 = 256 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+/// What [`Vault::import`] does with an entry that already exists in the vault.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ConflictPolicy {
+    /// Fails with [`CacheVaultError::ImportConflict`] and imports nothing.
+    #[default]
+    Fail,
+    /// Keeps the existing entry.
+    Skip,
+    /// Replaces the existing entry and its attributes.
+    Overwrite,
+}
+
+/// Contents of an archive, serialized with MessagePack before encryption.
+#[derive(Deserialize, Serialize)]
+struct Archive {
+    entries: Vec<ArchivedEntry>,
+    /// Configured [`Vault::history_limit`] of the namespaces in `entries`.
+    history_limits: HashMap<String, u32>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ArchivedEntry {
+    namespace: String,
+    key_name: String,
+    value: Vec<u8>,
+    content_type: Option<String>,
+    attributes: HashMap<String, String>,
+    sliding_ttl_millis: Option<i64>,
+    expired_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+}
+
+/// Replaces `path` with a new file holding `contents`, readable only by its owner on Unix.
+///
+/// The file is written next to `path` and renamed over it once complete, so a failed write leaves
+/// the previous file in place.
+pub(crate) async fn write_private(path: &Path, contents: &[u8]) -> Result<(), CacheVaultError> {
+    let path = path.to_path_buf();
+    let contents = contents.to_vec();
+    tokio::task::spawn_blocking(move || {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // Temporary files are created with mode 0600.
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(&contents)?;
+        file.as_file().sync_all()?;
+        file.persist(&path).map_err(|e| e.error)?;
+        Ok(())
+    })
+    .await
+    .map_err(|e| CacheVaultError::Unknown(format!("write task failed: {}", e)))?
+}
+
+fn seal(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>, CacheVaultError> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let params = Params::default();
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut archive = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    archive.extend_from_slice(MAGIC);
+    archive.extend_from_slice(&ARCHIVE_VERSION.to_le_bytes());
+    archive.extend_from_slice(&salt);
+    archive.extend_from_slice(&params.m_cost().to_le_bytes());
+    archive.extend_from_slice(&params.t_cost().to_le_bytes());
+    archive.extend_from_slice(&params.p_cost().to_le_bytes());
+    archive.extend_from_slice(&nonce);
+
+    let key = derive(passphrase, &salt, params)?;
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&key));
+    // The header is authenticated so that the KDF parameters cannot be tampered with.
+    let payload = Payload {
+        msg: plaintext,
+        aad: &archive,
+    };
+    let ciphertext = cipher.encrypt(&nonce, payload).map_err(CacheVaultError::ChaCha20)?;
+    archive.extend_from_slice(&ciphertext);
+    Ok(archive)
+}
+
+fn open(passphrase: &str, archive: &[u8]) -> Result<Vec<u8>, CacheVaultError> {
+    if archive.len() < HEADER_LEN || &archive[..8] != MAGIC {
+        return Err(CacheVaultError::InvalidArchive(String::from(
+            "not a cache-vault export",
+        )));
+    }
+    let (header, ciphertext) = archive.split_at(HEADER_LEN);
+    let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let version = u32_at(8);
+    if version != ARCHIVE_VERSION {
+        return Err(CacheVaultError::InvalidArchive(format!(
+            "unsupported archive version {}",
+            version
+        )));
+    }
+    let salt = &header[12..28];
+    let (m_cost, t_cost, p_cost) = (u32_at(28), u32_at(32), u32_at(36));
+    if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+        return Err(CacheVaultError::InvalidArchive(format!(
+            "key derivation costs m={}, t={}, p={} exceed the supported maximum",
+            m_cost, t_cost, p_cost
+        )));
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))?;
+    let nonce = &header[40..52];
+
+    let key = derive(passphrase, salt, params)?;
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&key));
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    cipher
+        .decrypt(GenericArray::from_slice(nonce), payload)
+        .map_err(|_| CacheVaultError::InvalidPassphrase)
+}
+
+impl Vault {
+    /// Writes every entry, with its attributes, expiry, content type and creation and update
+    /// times, and the history limits of their namespaces to a single file encrypted with a key
+    /// derived from `passphrase`. Previous values kept in the history are not exported.
+    ///
+    /// The file does not depend on this vault's keys, so it can be restored with [`Vault::import`]
+    /// on another machine. Expired entries that have not been purged yet are included. An existing
+    /// file at `path` is only replaced once the export has been written in full.
+    pub async fn export(&self, path: impl AsRef<Path>, passphrase: &str) -> Result<(), CacheVaultError> {
+        let mut keys: HashMap<String, NamespaceKeys> = HashMap::new();
+        let mut history_limits = HashMap::new();
+        let mut entries = Vec::new();
+        // One read transaction, so a concurrent save cannot change entries halfway through.
+        let mut tx = self.pool().begin().await?;
+        for entry in Entry::fetch_all(&mut *tx).await? {
+            if let Vacant(vacant) = keys.entry(entry.namespace.clone()) {
+                vacant.insert(existing_namespace_keys_in(&mut *tx, self.keys(), &entry.namespace).await?);
+                let history_limit = history_limit_in(&mut *tx, &entry.namespace).await?;
+                if history_limit > 0 {
+                    history_limits.insert(entry.namespace.clone(), history_limit);
+                }
+            }
+            let keys = &keys[&entry.namespace];
+            let attributes = Attribute::fetch_all(&mut *tx, entry.id)
+                .await?
+                .iter()
+                .map(|a| Ok((a.name.to_string(), a.plaintext(keys)?)))
+                .collect::<Result<HashMap<String, String>, CacheVaultError>>()?;
+            entries.push(ArchivedEntry {
+                value: entry.plaintext_bytes(keys)?,
+                namespace: entry.namespace,
+                key_name: entry.key_name,
+                content_type: entry.content_type,
+                attributes,
+                sliding_ttl_millis: entry.sliding_ttl_millis,
+                expired_at: entry.expired_at,
+                created_at: entry.created_at,
+                updated_at: entry.updated_at,
+            });
+        }
+        tx.commit().await?;
+        self.audit_all(
+            AuditOperation::Export,
+            entries.iter().map(|e| (e.namespace.as_str(), e.key_name.as_str())),
+        )
+        .await?;
+        let plaintext = rmp_serde::to_vec_named(&Archive {
+            entries,
+            history_limits,
+        })?;
+        let archive = seal(passphrase, &plaintext)?;
+        write_private(path.as_ref(), &archive).await
+    }
+
+    /// Saves the entries of a file written by [`Vault::export`], encrypting them with this vault's
+    /// keys, and returns how many were saved. The entries keep their creation and update times, and
+    /// the exported history limits replace those of their namespaces.
+    ///
+    /// Entries that already exist are handled according to `conflict_policy`. Everything is
+    /// imported in one transaction, so on error nothing is.
+    pub async fn import(
+        &self,
+        path: impl AsRef<Path>,
+        passphrase: &str,
+        conflict_policy: ConflictPolicy,
+    ) -> Result<u64, CacheVaultError> {
+        let archive = tokio::fs::read(path).await.map_err(CacheVaultError::Io)?;
+        let archive: Archive = rmp_serde::from_slice(&open(passphrase, &archive)?)?;
+
+        let entries = archive
+            .entries
+            .iter()
+            .map(|entry| NewEntry {
+                content_type: entry.content_type.as_deref(),
+                attributes: Some(entry.attributes.clone()),
+                expired_at: entry.expired_at,
+                sliding_ttl_millis: entry.sliding_ttl_millis,
+                timestamps: Some((entry.created_at, entry.updated_at)),
+                ..NewEntry::new(&entry.namespace, &entry.key_name, &entry.value)
+            })
+            .collect();
+        self.import_entries(entries, &archive.history_limits, conflict_policy)
+            .await
+    }
+
+    /// Sets `history_limits` and writes `entries` in one transaction, replacing their attributes,
+    /// and returns how many entries were written.
+    pub(crate) async fn import_entries(
+        &self,
+        entries: Vec<NewEntry<'_>>,
+        history_limits: &HashMap<String, u32>,
+        conflict_policy: ConflictPolicy,
+    ) -> Result<u64, CacheVaultError> {
+        let master = StaticKeyProvider::snapshot(self.keys())?;
+        let audit_key = self.audit_key(&master)?;
+        let mut keys: HashMap<&str, (NamespaceKeys, u32)> = HashMap::new();
+        let mut tx = self.pool().begin().await?;
+        for (namespace, limit) in history_limits {
+            set_history_limit_in(&mut tx, namespace, *limit).await?;
+        }
+        for entry in entries.iter() {
+            if let Vacant(vacant) = keys.entry(entry.namespace) {
+                let namespace_keys = namespace_keys_in(&mut tx, &master, entry.namespace).await?;
+                let history_limit = history_limit_in(&mut *tx, entry.namespace).await?;
+                vacant.insert((namespace_keys, history_limit));
+            }
+        }
+
+        let mut count = 0;
+        for entry in entries {
+            let (namespace, key_name) = (entry.namespace, entry.key_name);
+            let entry = NewEntry {
+                attributes_mode: AttributesMode::Replace,
+                expected_version: match conflict_policy {
+                    ConflictPolicy::Overwrite => None,
+                    ConflictPolicy::Fail | ConflictPolicy::Skip => Some(0),
+                },
+                ..entry
+            };
+            let (namespace_keys, history_limit) = &keys[namespace];
+            match entry
+                .write(&mut tx, namespace_keys, self.algorithm(), *history_limit)
+                .await
+            {
+                Err(CacheVaultError::VersionMismatch { .. }) => match conflict_policy {
+                    ConflictPolicy::Skip => continue,
+                    _ => {
+                        return Err(CacheVaultError::ImportConflict {
+                            namespace: namespace.to_string(),
+                            key_name: key_name.to_string(),
+                        })
+                    }
+                },
+                result => result?,
+            };
+            if let Some(audit_key) = &audit_key {
+                append(&mut tx, audit_key, AuditOperation::Save, namespace, key_name, "ok").await?;
+            }
+            count += 1;
+        }
+        tx.commit().await?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::tests::open_test_vault;
+    use chrono::{Duration, Utc};
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn test_export_and_import() -> Result<(), CacheVaultError> {
+        let (source, _source_dir) = open_test_vault().await?;
+        let expired_at = Utc::now().naive_utc() + Duration::hours(1);
+        let attributes = HashMap::from([(String::from("attr1"), String::from("attr1-value"))]);
+        source
+            .save(
+                "test",
+                "test-key1",
+                "test-value1",
+                Some(attributes.clone()),
+                Some(expired_at),
+            )
+            .await?;
+        source
+            .save_bytes("other", "test-key2", &[0, 159, 146, 150], None, None)
+            .await?;
+        source.set_history_limit("test", 3).await?;
+        let created_at = Utc::now().naive_utc() - Duration::days(30);
+        let updated_at = Utc::now().naive_utc() - Duration::days(1);
+        let entry = Entry::fetch(source.pool(), "test", "test-key1").await?;
+        Entry::set_timestamps(source.pool(), entry.id, created_at, updated_at).await?;
+        let file = NamedTempFile::new()?;
+        source.export(file.path(), "correct horse").await?;
+        #[cfg(unix)]
+        {
+            use std::io::Read;
+            use std::os::unix::fs::PermissionsExt;
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("export.cvx");
+            source.export(&path, "correct horse").await?;
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+            // A new export replaces the previous file instead of rewriting it in place.
+            let previous = std::fs::read(&path)?;
+            let mut previous_file = std::fs::File::open(&path)?;
+            source.export(&path, "correct horse").await?;
+            let mut contents = Vec::new();
+            previous_file.read_to_end(&mut contents)?;
+            assert_eq!(contents, previous);
+            assert_ne!(std::fs::read(&path)?, previous);
+            assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        }
+
+        let (destination, _destination_dir) = open_test_vault().await?;
+        assert!(matches!(
+            destination
+                .import(file.path(), "wrong horse", ConflictPolicy::Fail)
+                .await,
+            Err(CacheVaultError::InvalidPassphrase)
+        ));
+        assert_eq!(
+            destination
+                .import(file.path(), "correct horse", ConflictPolicy::Fail)
+                .await?,
+            2
+        );
+        let (value, imported_expired_at, attrs) = destination.fetch_with_attributes("test", "test-key1").await?;
+        assert_eq!(value, "test-value1");
+        assert_eq!(imported_expired_at, Some(expired_at));
+        assert_eq!(attrs, Some(attributes));
+        assert_eq!(
+            destination.fetch_bytes("other", "test-key2").await?.0,
+            vec![0, 159, 146, 150]
+        );
+        let entry = Entry::fetch(destination.pool(), "test", "test-key1").await?;
+        assert_eq!((entry.created_at, entry.updated_at), (created_at, updated_at));
+        assert_eq!(destination.history_limit("test").await?, 3);
+        assert_eq!(destination.history_limit("other").await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_conflict_policy() -> Result<(), CacheVaultError> {
+        let (source, _source_dir) = open_test_vault().await?;
+        source.save("test", "test-key1", "exported1", None, None).await?;
+        source.save("test", "test-key2", "exported2", None, None).await?;
+        let file = NamedTempFile::new()?;
+        source.export(file.path(), "passphrase").await?;
+
+        let (destination, _destination_dir) = open_test_vault().await?;
+        destination.save("test", "test-key1", "existing1", None, None).await?;
+        assert!(matches!(
+            destination
+                .import(file.path(), "passphrase", ConflictPolicy::Fail)
+                .await,
+            Err(CacheVaultError::ImportConflict { key_name, .. }) if key_name == "test-key1"
+        ));
+        assert!(destination.fetch("test", "test-key2").await.is_err());
+
+        assert_eq!(
+            destination
+                .import(file.path(), "passphrase", ConflictPolicy::Skip)
+                .await?,
+            1
+        );
+        assert_eq!(destination.fetch("test", "test-key1").await?.0, "existing1");
+        assert_eq!(destination.fetch("test", "test-key2").await?.0, "exported2");
+
+        assert_eq!(
+            destination
+                .import(file.path(), "passphrase", ConflictPolicy::Overwrite)
+                .await?,
+            2
+        );
+        assert_eq!(destination.fetch("test", "test-key1").await?.0, "exported1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_rejects_modified_archive() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault.save("test", "test-key", "test-value", None, None).await?;
+        let file = NamedTempFile::new()?;
+        vault.export(file.path(), "passphrase").await?;
+
+        let mut archive = std::fs::read(file.path())?;
+        let last = archive.len() - 1;
+        archive[last] ^= 1;
+        std::fs::write(file.path(), &archive)?;
+        assert!(vault
+            .import(file.path(), "passphrase", ConflictPolicy::Overwrite)
+            .await
+            .is_err());
+
+        // Oversized key derivation costs are rejected before anything is derived.
+        archive[last] ^= 1;
+        archive[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(file.path(), &archive)?;
+        assert!(matches!(
+            vault.import(file.path(), "passphrase", ConflictPolicy::Overwrite).await,
+            Err(CacheVaultError::InvalidArchive(_))
+        ));
+
+        std::fs::write(file.path(), b"not an archive")?;
+        assert!(matches!(
+            vault.import(file.path(), "passphrase", ConflictPolicy::Overwrite).await,
+            Err(CacheVaultError::InvalidArchive(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::NaiveDateTime;
+use sqlx::{SqliteConnection, SqliteExecutor};
+
+use crate::audit::{outcome, AuditOperation};
+use crate::error::CacheVaultError;
+use crate::models::{Entry, EntryVersion, Metadata};
+use crate::vault::Vault;
+
+const HISTORY_LIMIT: &str = "history_limit";
+
+/// Metadata of a previous value of an entry, as returned by [`Vault::history`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryEntry {
+    pub version: i64,
+    pub content_type: Option<String>,
+    /// When this value was saved.
+    pub created_at: NaiveDateTime,
+    /// When this value was overwritten.
+    pub archived_at: NaiveDateTime,
+}
+
+/// How many previous values to keep per entry in `namespace`; 0 unless configured.
+pub(crate) async fn history_limit_in<'e, E>(executor: E, namespace: &str) -> Result<u32, CacheVaultError>
+where
+    E: SqliteExecutor<'e>,
+{
+    let name = format!("{}.{}", HISTORY_LIMIT, namespace);
+    match Metadata::fetch_optional(executor, &name).await? {
+        Some(metadata) => {
+            let bytes = metadata
+                .value
+                .try_into()
+                .map_err(|_| CacheVaultError::Unknown(format!("metadata {:?} is malformed", name)))?;
+            Ok(u32::from_le_bytes(bytes))
+        }
+        None => Ok(0),
+    }
+}
+
+/// Stores the history limit of `namespace` and deletes the versions beyond it through `conn`.
+pub(crate) async fn set_history_limit_in(
+    conn: &mut SqliteConnection,
+    namespace: &str,
+    limit: u32,
+) -> Result<(), CacheVaultError> {
+    let name = format!("{}.{}", HISTORY_LIMIT, namespace);
+    Metadata::upsert(&mut *conn, &name, &limit.to_le_bytes()).await?;
+    EntryVersion::prune(&mut *conn, namespace, None, limit.into()).await?;
+    Ok(())
+}
+
+impl Vault {
+    /// Keeps the last `limit` overwritten values of every entry in `namespace`. 0, the default,
+    /// keeps none; lowering the limit deletes the excess right away.
+    pub async fn set_history_limit(&self, namespace: &str, limit: u32) -> Result<(), CacheVaultError> {
+        let mut tx = self.pool().begin().await?;
+        set_history_limit_in(&mut tx, namespace, limit).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn history_limit(&self, namespace: &str) -> Result<u32, CacheVaultError> {
+        history_limit_in(self.pool(), namespace).await
+    }
+
+    /// Previous values of the entry, newest first, without decrypting them.
+    pub async fn history(&self, namespace: &str, key_name: &str) -> Result<Vec<HistoryEntry>, CacheVaultError> {
+        let entry = Entry::fetch(self.pool(), namespace, key_name).await?;
+        let versions = EntryVersion::fetch_all(self.pool(), entry.id).await?;
+        Ok(versions
+            .into_iter()
+            .map(|v| HistoryEntry {
+                version: v.version,
+                content_type: v.content_type,
+                created_at: v.created_at,
+                archived_at: v.archived_at,
+            })
+            .collect())
+    }
+
+    /// The value the entry had at `version`, one of those listed by [`Vault::history`].
+    pub async fn fetch_history(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        version: i64,
+    ) -> Result<String, CacheVaultError> {
+        let result = self.decrypt_version(namespace, key_name, version).await;
+        self.audit(AuditOperation::FetchHistory, namespace, key_name, outcome(&result))
+            .await?;
+        result
+    }
+
+    async fn decrypt_version(&self, namespace: &str, key_name: &str, version: i64) -> Result<String, CacheVaultError> {
+        let entry = Entry::fetch(self.pool(), namespace, key_name).await?;
+        let entry_version = EntryVersion::fetch(self.pool(), entry.id, version).await?;
+        let keys = self.existing_namespace_keys(namespace).await?;
+        String::from_utf8(entry_version.plaintext_bytes(&keys, namespace, key_name)?).map_err(|_| {
+            CacheVaultError::NonUtf8Value {
+                namespace: namespace.to_string(),
+                key_name: key_name.to_string(),
+            }
+        })
+    }
+
+    /// Makes the value the entry had at `version` current again, returning the entry's new
+    /// version. The value being replaced is kept in the history like on any save.
+    pub async fn restore_version(&self, namespace: &str, key_name: &str, version: i64) -> Result<i64, CacheVaultError> {
+        let limit = self.history_limit(namespace).await?;
+        let mut tx = self.pool().begin().await?;
+        let entry = Entry::fetch(&mut *tx, namespace, key_name).await?;
+        let entry_version = EntryVersion::fetch(&mut *tx, entry.id, version).await?;
+        if limit > 0 {
+            EntryVersion::archive(&mut *tx, namespace, key_name, None).await?;
+        }
+        Entry::restore(&mut *tx, &entry_version).await?;
+        EntryVersion::prune(&mut *tx, namespace, Some(key_name), limit.into()).await?;
+        self.audit_in(&mut tx, AuditOperation::Restore, namespace, key_name, "ok")
+            .await?;
+        tx.commit().await?;
+        Ok(entry.version + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::tests::open_test_vault;
+
+    #[tokio::test]
+    async fn test_history() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault.set_history_limit("test", 2).await?;
+        assert_eq!(vault.history_limit("test").await?, 2);
+        assert_eq!(vault.history_limit("other").await?, 0);
+
+        for value in ["token1", "token2", "token3", "token4"] {
+            vault.save("test", "token", value, None, None).await?;
+            vault.save("other", "token", value, None, None).await?;
+        }
+        let history = vault.history("test", "token").await?;
+        assert_eq!(history.iter().map(|h| h.version).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(vault.fetch_history("test", "token", 2).await?, "token2");
+        assert!(vault.fetch_history("test", "token", 1).await.is_err());
+        assert!(vault.history("other", "token").await?.is_empty());
+
+        assert_eq!(vault.restore_version("test", "token", 2).await?, 5);
+        assert_eq!(
+            vault.fetch_with_version("test", "token").await?,
+            (String::from("token2"), None, 5)
+        );
+        let history = vault.history("test", "token").await?;
+        assert_eq!(history.iter().map(|h| h.version).collect::<Vec<_>>(), vec![4, 3]);
+        assert_eq!(vault.fetch_history("test", "token", 4).await?, "token4");
+
+        vault.set_history_limit("test", 0).await?;
+        assert!(vault.history("test", "token").await?.is_empty());
+        Ok(())
+    }
+}
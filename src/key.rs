@@ -2,10 +2,59 @@
 use chacha20poly1305::aead::{KeyInit, OsRng};
 use chacha20poly1305::ChaCha20Poly1305;
 use keyring::Entry;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
 
 use crate::base32::{decode, encode};
 use crate::error::CacheVaultError;
 
+/// Length of the pepper every provider returns.
+const PEPPER_LEN: usize = 32;
+
+/// Source of the encryption key and the pepper used by a [`Vault`](crate::Vault).
+pub trait KeyProvider: Send + Sync {
+    fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError>;
+
+    fn pepper(&self) -> Result<Vec<u8>, CacheVaultError>;
+
+    /// Previous encryption keys that are still accepted for decryption, e.g. during [`Vault::rotate_key`](crate::Vault::rotate_key).
+    fn decryption_keys(&self) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Rejects a pepper that is not `PEPPER_LEN` bytes long, as attribute digests and the audit key
+/// are derived from it.
+fn check_pepper(pepper: Vec<u8>) -> Result<Vec<u8>, CacheVaultError> {
+    if pepper.len() != PEPPER_LEN {
+        return Err(CacheVaultError::InvalidPepperLength(pepper.len()));
+    }
+    Ok(pepper)
+}
+
+/// Keys that may decrypt a row written under `key_id`, or every known key for rows written before
+/// key ids were recorded.
+pub(crate) fn candidate_keys(keys: &dyn KeyProvider, key_id: Option<&str>) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+    let mut candidates = vec![keys.encryption_key()?];
+    candidates.extend(keys.decryption_keys()?);
+    match key_id {
+        Some(key_id) => {
+            let mut matched = Vec::new();
+            for key in candidates {
+                if crate::crypt::key_id(&key)? == key_id {
+                    matched.push(key);
+                }
+            }
+            if matched.is_empty() {
+                return Err(CacheVaultError::UnknownKeyId(key_id.to_string()));
+            }
+            Ok(matched)
+        }
+        None => Ok(candidates),
+    }
+}
+
 #[derive(Debug)]
 pub struct Key {
     service: &'static str,
@@ -13,22 +62,26 @@ pub struct Key {
 }
 
 fn generate_key() -> String {
-    let key = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
-    encode(&key)
+    encode(&generate_key_bytes())
 }
 
-impl Key {
-    #[allow(dead_code)]
-    pub fn new(service: &'static str, user: &'static str) -> Self {
-        Self { service, user }
-    }
+pub(crate) fn generate_key_bytes() -> Vec<u8> {
+    ChaCha20Poly1305::generate_key(&mut OsRng).to_vec()
+}
 
-    pub fn default() -> Self {
+impl Default for Key {
+    fn default() -> Self {
         Self {
             service: "cache-vault",
             user: "encryption-key",
         }
     }
+}
+
+impl Key {
+    pub fn new(service: &'static str, user: &'static str) -> Self {
+        Self { service, user }
+    }
 
     pub fn pepper() -> Self {
         Self {
@@ -53,6 +106,12 @@ impl Key {
         }
     }
 
+    /// Like [`Key::get`] but never generates a missing key.
+    pub fn existing(&self) -> Result<Vec<u8>, CacheVaultError> {
+        let key_str = self.entry()?.get_password().map_err(CacheVaultError::Keyring)?;
+        decode(key_str.as_str()).ok_or(CacheVaultError::Decode)
+    }
+
     #[allow(dead_code)]
     pub fn delete(&self) -> Result<(), CacheVaultError> {
         let entry = self.entry()?;
@@ -64,6 +123,262 @@ impl Key {
     }
 }
 
+/// Keys stored in the OS keyring, generated on first use.
+#[derive(Debug)]
+pub struct KeyringKeyProvider {
+    encryption_key: Key,
+    pepper: Key,
+    previous: Vec<Key>,
+}
+
+impl KeyringKeyProvider {
+    pub fn new(encryption_key: Key, pepper: Key) -> Self {
+        Self {
+            encryption_key,
+            pepper,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Accepts a previous encryption key for decryption only.
+    pub fn with_previous(mut self, key: Key) -> Self {
+        self.previous.push(key);
+        self
+    }
+}
+
+impl Default for KeyringKeyProvider {
+    fn default() -> Self {
+        Self::new(Key::default(), Key::pepper())
+    }
+}
+
+impl KeyProvider for KeyringKeyProvider {
+    fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError> {
+        self.encryption_key.get()
+    }
+
+    fn pepper(&self) -> Result<Vec<u8>, CacheVaultError> {
+        check_pepper(self.pepper.get()?)
+    }
+
+    fn decryption_keys(&self) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+        self.previous.iter().map(|key| key.existing()).collect()
+    }
+}
+
+/// Keys read from base32-encoded environment variables.
+///
+/// Defaults to `CACHE_VAULT_ENCRYPTION_KEY` and `CACHE_VAULT_PEPPER`. Previous encryption keys may
+/// be listed comma-separated in `CACHE_VAULT_PREVIOUS_ENCRYPTION_KEYS`.
+#[derive(Debug)]
+pub struct EnvKeyProvider {
+    encryption_key_var: String,
+    pepper_var: String,
+    previous_keys_var: String,
+}
+
+impl EnvKeyProvider {
+    pub fn new(encryption_key_var: impl Into<String>, pepper_var: impl Into<String>) -> Self {
+        Self {
+            encryption_key_var: encryption_key_var.into(),
+            pepper_var: pepper_var.into(),
+            previous_keys_var: String::from("CACHE_VAULT_PREVIOUS_ENCRYPTION_KEYS"),
+        }
+    }
+
+    pub fn with_previous_keys_var(mut self, previous_keys_var: impl Into<String>) -> Self {
+        self.previous_keys_var = previous_keys_var.into();
+        self
+    }
+
+    fn get(var: &str) -> Result<Vec<u8>, CacheVaultError> {
+        let value = std::env::var(var).map_err(|_| CacheVaultError::MissingEnv(var.to_string()))?;
+        decode(value.trim()).ok_or(CacheVaultError::Decode)
+    }
+}
+
+impl Default for EnvKeyProvider {
+    fn default() -> Self {
+        Self::new("CACHE_VAULT_ENCRYPTION_KEY", "CACHE_VAULT_PEPPER")
+    }
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Self::get(&self.encryption_key_var)
+    }
+
+    fn pepper(&self) -> Result<Vec<u8>, CacheVaultError> {
+        check_pepper(Self::get(&self.pepper_var)?)
+    }
+
+    fn decryption_keys(&self) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+        let Ok(value) = std::env::var(&self.previous_keys_var) else {
+            return Ok(Vec::new());
+        };
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(|k| decode(k).ok_or(CacheVaultError::Decode))
+            .collect()
+    }
+}
+
+/// Keys stored in a local file as base32-encoded lines: the encryption key, the pepper and then
+/// any previous encryption keys.
+///
+/// The file is created with mode `0600` on first use and is refused if it is readable by
+/// anyone other than its owner.
+#[derive(Debug)]
+pub struct FileKeyProvider {
+    path: PathBuf,
+}
+
+impl FileKeyProvider {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn read(&self) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+        if !self.path.exists() {
+            self.create()?;
+        }
+        self.check_permissions()?;
+        let keys = fs::read_to_string(&self.path)?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| decode(l).ok_or(CacheVaultError::Decode))
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.len() < 2 {
+            return Err(CacheVaultError::Decode);
+        }
+        Ok(keys)
+    }
+
+    fn create(&self) -> Result<(), CacheVaultError> {
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&self.path)?;
+        writeln!(file, "{}", generate_key())?;
+        writeln!(file, "{}", generate_key())?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn check_permissions(&self) -> Result<(), CacheVaultError> {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&self.path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(CacheVaultError::InsecureKeyFile(self.path.clone()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_permissions(&self) -> Result<(), CacheVaultError> {
+        Ok(())
+    }
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Ok(self.read()?.swap_remove(0))
+    }
+
+    fn pepper(&self) -> Result<Vec<u8>, CacheVaultError> {
+        check_pepper(self.read()?.swap_remove(1))
+    }
+
+    fn decryption_keys(&self) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+        Ok(self.read()?.split_off(2))
+    }
+}
+
+/// Fixed keys held in memory, e.g. for tests or keys obtained from an external secret manager.
+#[derive(Clone)]
+pub struct StaticKeyProvider {
+    encryption_key: Vec<u8>,
+    pepper: Vec<u8>,
+    previous: Vec<Vec<u8>>,
+}
+
+impl StaticKeyProvider {
+    /// Fails with [`CacheVaultError::InvalidPepperLength`] unless `pepper` is 32 bytes.
+    pub fn new(encryption_key: Vec<u8>, pepper: Vec<u8>) -> Result<Self, CacheVaultError> {
+        Ok(Self {
+            encryption_key,
+            pepper: check_pepper(pepper)?,
+            previous: Vec::new(),
+        })
+    }
+
+    /// Accepts a previous encryption key for decryption only.
+    pub fn with_previous(mut self, key: Vec<u8>) -> Self {
+        self.previous.push(key);
+        self
+    }
+
+    /// Random keys that only live as long as this provider.
+    pub fn generate() -> Self {
+        Self {
+            encryption_key: generate_key_bytes(),
+            pepper: generate_key_bytes(),
+            previous: Vec::new(),
+        }
+    }
+
+    /// Copies the current keys of `keys`, so a batch of operations reads them only once.
+    pub(crate) fn snapshot(keys: &dyn KeyProvider) -> Result<Self, CacheVaultError> {
+        Ok(Self {
+            encryption_key: keys.encryption_key()?,
+            pepper: keys.pepper()?,
+            previous: keys.decryption_keys()?,
+        })
+    }
+}
+
+impl std::fmt::Debug for StaticKeyProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticKeyProvider").finish_non_exhaustive()
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Ok(self.encryption_key.clone())
+    }
+
+    fn pepper(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Ok(self.pepper.clone())
+    }
+
+    fn decryption_keys(&self) -> Result<Vec<Vec<u8>>, CacheVaultError> {
+        Ok(self.previous.clone())
+    }
+}
+
+/// Placeholder for a passphrase-protected vault that has not been unlocked yet.
+#[derive(Debug)]
+pub(crate) struct LockedKeyProvider;
+
+impl KeyProvider for LockedKeyProvider {
+    fn encryption_key(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Err(CacheVaultError::Locked)
+    }
+
+    fn pepper(&self) -> Result<Vec<u8>, CacheVaultError> {
+        Err(CacheVaultError::Locked)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,4 +396,71 @@ mod tests {
         println!("{:?}", pw);
         k.delete().unwrap();
     }
+
+    #[test]
+    fn test_env_key_provider() -> Result<(), CacheVaultError> {
+        let provider = EnvKeyProvider::new("CACHE_VAULT_TEST_ENCRYPTION_KEY", "CACHE_VAULT_TEST_PEPPER");
+        assert!(matches!(provider.encryption_key(), Err(CacheVaultError::MissingEnv(_))));
+
+        let key = generate_key();
+        std::env::set_var("CACHE_VAULT_TEST_ENCRYPTION_KEY", &key);
+        std::env::set_var("CACHE_VAULT_TEST_PEPPER", generate_key());
+        assert_eq!(provider.encryption_key()?, decode(&key).unwrap());
+        assert_eq!(provider.pepper()?.len(), 32);
+
+        std::env::set_var("CACHE_VAULT_TEST_PEPPER", encode(b"pep"));
+        assert!(matches!(
+            provider.pepper(),
+            Err(CacheVaultError::InvalidPepperLength(3))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_key_provider() -> Result<(), CacheVaultError> {
+        let provider = StaticKeyProvider::new(generate_key_bytes(), generate_key_bytes())?;
+        assert_eq!(provider.pepper()?.len(), PEPPER_LEN);
+        assert!(matches!(
+            StaticKeyProvider::new(generate_key_bytes(), b"pep".to_vec()),
+            Err(CacheVaultError::InvalidPepperLength(3))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_key_provider() -> Result<(), CacheVaultError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("keys");
+        let provider = FileKeyProvider::new(&path);
+        let key = provider.encryption_key()?;
+        let pepper = provider.pepper()?;
+        assert_eq!(key.len(), 32);
+        assert_eq!(pepper.len(), 32);
+        assert_ne!(key, pepper);
+        assert_eq!(FileKeyProvider::new(&path).encryption_key()?, key);
+
+        let short = dir.path().join("short");
+        fs::write(&short, format!("{}\n{}\n", encode(&key), encode(b"pep")))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&short, fs::Permissions::from_mode(0o600))?;
+        }
+        assert!(matches!(
+            FileKeyProvider::new(&short).pepper(),
+            Err(CacheVaultError::InvalidPepperLength(3))
+        ));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+            assert!(matches!(
+                provider.encryption_key(),
+                Err(CacheVaultError::InsecureKeyFile(_))
+            ));
+        }
+        Ok(())
+    }
 }
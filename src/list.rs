@@ -0,0 +1,177 @@
+use chrono::{NaiveDateTime, Utc};
+
+use crate::error::CacheVaultError;
+use crate::models::Entry;
+use crate::vault::Vault;
+
+/// Metadata of a stored entry, as returned by [`Vault::list`].
+#[derive(Clone, Debug, Eq, PartialEq, sqlx::FromRow)]
+pub struct EntryInfo {
+    pub key_name: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub expired_at: Option<NaiveDateTime>,
+}
+
+/// Column [`Vault::list`] sorts by. Ties are broken by key name.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ListOrder {
+    #[default]
+    KeyName,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl ListOrder {
+    pub(crate) fn column(&self) -> &'static str {
+        match self {
+            ListOrder::KeyName => "key_name",
+            ListOrder::CreatedAt => "created_at",
+            ListOrder::UpdatedAt => "updated_at",
+        }
+    }
+}
+
+/// Filtering and pagination for [`Vault::list`].
+///
+/// Pages can be walked either with [`ListOptions::offset`] or, more efficiently and stable under
+/// concurrent writes, by passing the last entry of the previous page to [`ListOptions::after`].
+#[derive(Clone, Debug, Default)]
+pub struct ListOptions {
+    pub(crate) prefix: Option<String>,
+    pub(crate) order: ListOrder,
+    pub(crate) descending: bool,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+    pub(crate) after: Option<EntryInfo>,
+    pub(crate) include_expired: bool,
+}
+
+impl ListOptions {
+    /// Only keys starting with `prefix`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn order(mut self, order: ListOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn descending(mut self, descending: bool) -> Self {
+        self.descending = descending;
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Continues after `entry`, which must come from a listing with the same order.
+    pub fn after(mut self, entry: EntryInfo) -> Self {
+        self.after = Some(entry);
+        self
+    }
+
+    /// Also list entries whose `expired_at` has passed. They are left out by default.
+    pub fn include_expired(mut self, include_expired: bool) -> Self {
+        self.include_expired = include_expired;
+        self
+    }
+}
+
+impl Vault {
+    /// Lists the entries in `namespace` without decrypting anything, so no key is needed.
+    pub async fn list(&self, namespace: &str, options: ListOptions) -> Result<Vec<EntryInfo>, CacheVaultError> {
+        Entry::list(self.pool(), namespace, &options, Utc::now().naive_utc()).await
+    }
+
+    /// Names of all namespaces that have at least one entry, sorted.
+    pub async fn list_namespaces(&self) -> Result<Vec<String>, CacheVaultError> {
+        Entry::namespaces(self.pool()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::{LockedKeyProvider, StaticKeyProvider};
+    use crate::vault::tests::{open_test_vault, test_database_path};
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn test_list() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let past = Utc::now().naive_utc() - Duration::seconds(1);
+        for key_name in ["aws/prod", "aws/dev", "gcp/prod", "aws/stage"] {
+            vault.save("test", key_name, "value", None, None).await?;
+        }
+        vault.save("test", "aws/old", "value", None, Some(past)).await?;
+        vault.save("other", "aws/prod", "value", None, None).await?;
+
+        let names = |entries: Vec<EntryInfo>| entries.into_iter().map(|e| e.key_name).collect::<Vec<_>>();
+        assert_eq!(
+            names(vault.list("test", ListOptions::default()).await?),
+            vec!["aws/dev", "aws/prod", "aws/stage", "gcp/prod"]
+        );
+        assert_eq!(
+            names(vault.list("test", ListOptions::default().include_expired(true)).await?),
+            vec!["aws/dev", "aws/old", "aws/prod", "aws/stage", "gcp/prod"]
+        );
+        assert_eq!(
+            names(
+                vault
+                    .list("test", ListOptions::default().prefix("aws/").descending(true))
+                    .await?
+            ),
+            vec!["aws/stage", "aws/prod", "aws/dev"]
+        );
+        assert_eq!(
+            names(vault.list("test", ListOptions::default().limit(2).offset(1)).await?),
+            vec!["aws/prod", "aws/stage"]
+        );
+        // `_` and `%` are not wildcards.
+        assert!(vault
+            .list("test", ListOptions::default().prefix("aws_"))
+            .await?
+            .is_empty());
+
+        let options = ListOptions::default().order(ListOrder::CreatedAt).limit(3);
+        let page1 = vault.list("test", options.clone()).await?;
+        let page2 = vault.list("test", options.after(page1[2].clone())).await?;
+        assert_eq!(page1.len(), 3);
+        assert_eq!(page2.len(), 1);
+        assert!(!page1.contains(&page2[0]));
+
+        assert_eq!(vault.list_namespaces().await?, vec!["other", "test"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_without_keys() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(StaticKeyProvider::generate())
+            .open()
+            .await?;
+        vault.save("test", "test-key", "value", None, None).await?;
+        vault.pool().close().await;
+
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(LockedKeyProvider)
+            .open()
+            .await?;
+        assert_eq!(vault.list("test", ListOptions::default()).await?.len(), 1);
+        assert_eq!(vault.list_namespaces().await?, vec!["test"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use cache_vault::{CacheVaultError, EnvKeyProvider, ListOptions, Vault};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, IsTerminal, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Reads and writes secrets in a cache-vault database.
+///
+/// Keys come from the OS keyring, or from $CACHE_VAULT_ENCRYPTION_KEY and $CACHE_VAULT_PEPPER when
+/// those are set. A passphrase-protected vault is unlocked with $CACHE_VAULT_PASSPHRASE, or with a
+/// passphrase prompted for on the terminal.
+#[derive(Debug, Parser)]
+#[command(name = "cache-vault")]
+struct Args {
+    /// Database file [default: $CACHE_VAULT_DATABASE_PATH or the config dir]
+    #[arg(long, global = true, value_name = "PATH")]
+    db: Option<PathBuf>,
+    /// Namespace of the keys
+    #[arg(short, long, global = true, env = "CACHE_VAULT_NAMESPACE", default_value = "default")]
+    namespace: String,
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Unlock a passphrase-protected vault; implied when $CACHE_VAULT_PASSPHRASE is set
+    #[arg(long, global = true)]
+    passphrase: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Eq, PartialEq, Subcommand)]
+enum Command {
+    /// Print the value of KEY
+    Get {
+        #[arg(value_name = "KEY")]
+        key_name: String,
+    },
+    /// Save KEY with the value read from stdin, or prompted for on a terminal
+    Set {
+        #[arg(value_name = "KEY")]
+        key_name: String,
+        /// Attribute to save with the value; may be repeated
+        #[arg(long = "attr", value_name = "NAME=VALUE", value_parser = parse_attribute)]
+        attributes: Vec<(String, String)>,
+        /// Expire the value after SECONDS
+        #[arg(long = "ttl", value_name = "SECONDS", value_parser = parse_ttl)]
+        expired_at: Option<NaiveDateTime>,
+    },
+    /// Delete KEY and its attributes
+    Rm {
+        #[arg(value_name = "KEY")]
+        key_name: String,
+    },
+    /// List the keys in the namespace
+    Ls,
+    /// Print the attributes of KEY
+    Attrs {
+        #[arg(value_name = "KEY")]
+        key_name: String,
+    },
+    /// Delete all expired entries
+    Purge,
+    /// Apply pending database migrations
+    Migrate,
+}
+
+fn parse_attribute(attribute: &str) -> Result<(String, String), String> {
+    attribute
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| String::from("expected NAME=VALUE"))
+}
+
+/// When a value saved now with a TTL of `seconds` expires.
+fn parse_ttl(seconds: &str) -> Result<NaiveDateTime, String> {
+    let seconds = seconds
+        .parse::<i64>()
+        .ok()
+        .filter(|seconds| *seconds > 0)
+        .ok_or_else(|| String::from("expected a positive number of seconds"))?;
+    TimeDelta::try_seconds(seconds)
+        .and_then(|ttl| Utc::now().naive_utc().checked_add_signed(ttl))
+        .ok_or_else(|| String::from("too far in the future"))
+}
+
+/// The value for `set`: prompted for on a terminal, otherwise all of stdin with a single trailing
+/// newline dropped.
+fn read_value(namespace: &str, key_name: &str) -> io::Result<String> {
+    if io::stdin().is_terminal() {
+        return rpassword::prompt_password(format!("Value for {}/{}: ", namespace, key_name));
+    }
+    let mut value = String::new();
+    io::stdin().read_to_string(&mut value)?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    Ok(value)
+}
+
+fn format_time(time: Option<NaiveDateTime>) -> String {
+    time.map(|time| time.format("%Y-%m-%dT%H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+async fn open(args: &Args) -> Result<Vault, CacheVaultError> {
+    let mut builder = Vault::builder();
+    if let Some(db) = &args.db {
+        builder = builder.path(db);
+    }
+    let passphrase = std::env::var("CACHE_VAULT_PASSPHRASE").ok();
+    if args.passphrase || passphrase.is_some() {
+        let mut vault = builder.passphrase_protected().open().await?;
+        let passphrase = match passphrase {
+            Some(passphrase) => passphrase,
+            None => rpassword::prompt_password("Passphrase: ")?,
+        };
+        vault.unlock(&passphrase).await?;
+        return Ok(vault);
+    }
+    if std::env::var_os("CACHE_VAULT_ENCRYPTION_KEY").is_some() {
+        builder = builder.key_provider(EnvKeyProvider::default());
+    }
+    builder.open().await
+}
+
+async fn run(args: Args) -> Result<(), CacheVaultError> {
+    let namespace = args.namespace.as_str();
+    let vault = open(&args).await?;
+    match &args.command {
+        Command::Get { key_name } => {
+            let (value, expired_at) = vault.fetch(namespace, key_name).await?;
+            match args.format {
+                Format::Text => println!("{}", value),
+                Format::Json => println!(
+                    "{}",
+                    json!({"namespace": namespace, "key_name": key_name, "value": value, "expired_at": expired_at})
+                ),
+            }
+        }
+        Command::Set {
+            key_name,
+            attributes,
+            expired_at,
+        } => {
+            let value = read_value(namespace, key_name)?;
+            let attributes =
+                Some(attributes.iter().cloned().collect::<HashMap<_, _>>()).filter(|attributes| !attributes.is_empty());
+            vault.save(namespace, key_name, &value, attributes, *expired_at).await?;
+        }
+        Command::Rm { key_name } => {
+            if !vault.delete(namespace, key_name).await? {
+                return Err(CacheVaultError::SqlxError(sqlx::Error::RowNotFound));
+            }
+        }
+        Command::Ls => {
+            let entries = vault.list(namespace, ListOptions::default()).await?;
+            match args.format {
+                Format::Text => {
+                    for entry in entries {
+                        println!("{}\t{}", entry.key_name, format_time(entry.expired_at));
+                    }
+                }
+                Format::Json => {
+                    let entries = entries
+                        .into_iter()
+                        .map(|entry| {
+                            json!({
+                                "key_name": entry.key_name,
+                                "created_at": entry.created_at,
+                                "updated_at": entry.updated_at,
+                                "expired_at": entry.expired_at,
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    println!("{}", json!(entries));
+                }
+            }
+        }
+        Command::Attrs { key_name } => {
+            let (_, _, attributes) = vault.fetch_with_attributes(namespace, key_name).await?;
+            let attributes = attributes.unwrap_or_default().into_iter().collect::<BTreeMap<_, _>>();
+            match args.format {
+                Format::Text => {
+                    for (name, value) in attributes {
+                        println!("{}={}", name, value);
+                    }
+                }
+                Format::Json => println!("{}", json!(attributes)),
+            }
+        }
+        Command::Purge => {
+            let count = vault.purge_expired().await?;
+            match args.format {
+                Format::Text => println!("{}", count),
+                Format::Json => println!("{}", json!({"purged": count})),
+            }
+        }
+        // Opening the vault has applied them.
+        Command::Migrate => {}
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CacheVaultError::SqlxError(sqlx::Error::RowNotFound)) | Err(CacheVaultError::Expired { .. }) => {
+            eprintln!("cache-vault: key not found");
+            ExitCode::FAILURE
+        }
+        Err(CacheVaultError::PassphraseProtected) => {
+            eprintln!("cache-vault: vault is passphrase protected, pass --passphrase or set CACHE_VAULT_PASSPHRASE");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("cache-vault: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("cache-vault").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_parse_args() -> Result<(), clap::Error> {
+        let args = parse(&[
+            "--db=/tmp/vault.db",
+            "--passphrase",
+            "set",
+            "token",
+            "-n",
+            "aws",
+            "--attr",
+            "env=prod",
+            "--ttl",
+            "60",
+            "--format",
+            "json",
+        ])?;
+        assert_eq!(args.db, Some(PathBuf::from("/tmp/vault.db")));
+        assert_eq!(args.namespace, "aws");
+        assert_eq!(args.format, Format::Json);
+        assert!(args.passphrase);
+        let Command::Set {
+            key_name,
+            attributes,
+            expired_at,
+        } = args.command
+        else {
+            panic!("unexpected command {:?}", args.command);
+        };
+        assert_eq!(key_name, "token");
+        assert_eq!(attributes, vec![(String::from("env"), String::from("prod"))]);
+        let remaining = expired_at.unwrap() - Utc::now().naive_utc();
+        assert!(remaining > Duration::seconds(50) && remaining <= Duration::seconds(60));
+        let args = parse(&["ls"])?;
+        assert_eq!(args.command, Command::Ls);
+        assert!(!args.passphrase);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        for args in [
+            &[][..],
+            &["get"],
+            &["frobnicate"],
+            &["get", "a", "b"],
+            &["ls", "--format", "yaml"],
+            &["set", "a", "--attr", "novalue"],
+            &["set", "a", "--ttl", "-1"],
+            &["set", "a", "--ttl", "10000000000000000"],
+            &["set", "a", "--ttl", "9223372036854775807"],
+            &["get", "a", "--ttl", "60"],
+            &["ls", "--db"],
+            &["ls", "--verbose"],
+        ] {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+}
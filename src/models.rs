@@ -1,18 +1,32 @@
 use anyhow::Context;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{QueryBuilder, Sqlite, SqliteExecutor};
 
-use crate::connection::POOL;
-use crate::crypt::{decrypt, encrypt};
+use crate::crypt::{associated_data, decrypt, decrypt_bytes, encrypt, encrypt_bytes, key_id, Algorithm};
 use crate::digest::digest;
 use crate::error::CacheVaultError;
+use crate::key::{candidate_keys, generate_key_bytes, KeyProvider};
+use crate::list::{EntryInfo, ListOptions, ListOrder};
 
-#[derive(Debug, Eq, PartialEq)]
+/// Ciphertext format written by this version.
+///
+/// 1. no associated data
+/// 2. namespace and key name (entries) or entry id and name (attributes) bound as associated data
+pub const FORMAT_VERSION: i64 = 2;
+
+#[derive(Debug, Eq, PartialEq, sqlx::FromRow)]
 pub struct Entry {
     pub id: i64,
     pub namespace: String,
     pub key_name: String,
     pub nonce: Vec<u8>,
     pub encrypted_value: Vec<u8>,
+    pub key_id: Option<String>,
+    pub format_version: i64,
+    pub algorithm: String,
+    pub content_type: Option<String>,
+    pub sliding_ttl_millis: Option<i64>,
+    pub version: i64,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub expired_at: Option<NaiveDateTime>,
@@ -26,16 +40,101 @@ pub struct Attribute {
     pub nonce: Vec<u8>,
     pub encrypted_value: Vec<u8>,
     pub hashed_value: Vec<u8>,
+    pub key_id: Option<String>,
+    pub format_version: i64,
+    pub algorithm: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct NamespaceKey {
+    pub id: i64,
+    pub namespace: String,
+    pub nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+    pub key_id: String,
+    pub data_key_id: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A previous value of an entry, kept when the entry was overwritten.
+#[derive(Debug, Eq, PartialEq)]
+pub struct EntryVersion {
+    pub id: i64,
+    pub entry_id: i64,
+    pub version: i64,
+    pub nonce: Vec<u8>,
+    pub encrypted_value: Vec<u8>,
+    pub key_id: Option<String>,
+    pub format_version: i64,
+    pub algorithm: String,
+    pub content_type: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub archived_at: NaiveDateTime,
+}
+
+/// One record of the audit log, chained to the previous record through `hash`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct AuditLog {
+    pub id: i64,
+    pub operation: String,
+    pub namespace: String,
+    pub key_name: String,
+    pub pid: i64,
+    pub executable: Option<String>,
+    pub outcome: String,
+    pub created_at: NaiveDateTime,
+    /// Only missing between the insert of the record and its linking into the chain.
+    pub hash: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub value: Vec<u8>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
 
 impl Entry {
-    pub fn plaintext(&self) -> Result<String, CacheVaultError> {
-        decrypt(&self.nonce, &self.encrypted_value)
+    pub fn associated_data(namespace: &str, key_name: &str) -> Vec<u8> {
+        associated_data(&[b"entries", namespace.as_bytes(), key_name.as_bytes()])
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expired_at
+            .is_some_and(|expired_at| expired_at <= Utc::now().naive_utc())
+    }
+
+    pub fn plaintext(&self, keys: &dyn KeyProvider) -> Result<String, CacheVaultError> {
+        String::from_utf8(self.plaintext_bytes(keys)?).map_err(|_| CacheVaultError::NonUtf8Value {
+            namespace: self.namespace.clone(),
+            key_name: self.key_name.clone(),
+        })
+    }
+
+    pub fn plaintext_bytes(&self, keys: &dyn KeyProvider) -> Result<Vec<u8>, CacheVaultError> {
+        let aad = match self.format_version {
+            1 => Vec::new(),
+            _ => Self::associated_data(&self.namespace, &self.key_name),
+        };
+        decrypt_row(
+            keys,
+            self.key_id.as_deref(),
+            self.algorithm.parse()?,
+            &aad,
+            &self.nonce,
+            &self.encrypted_value,
+            decrypt_bytes,
+        )
     }
 
-    pub async fn fetch(namespace: &str, key_name: &str) -> Result<Self, CacheVaultError> {
+    pub async fn fetch<'e, E>(executor: E, namespace: &str, key_name: &str) -> Result<Self, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
         let entry = sqlx::query_as!(
             Entry,
             r#"
@@ -45,6 +144,12 @@ impl Entry {
               , key_name
               , nonce
               , encrypted_value
+              , key_id
+              , format_version
+              , algorithm
+              , content_type
+              , sliding_ttl_millis
+              , version
               , created_at
               , updated_at
               , expired_at
@@ -58,13 +163,44 @@ impl Entry {
             namespace,
             key_name
         )
-        .fetch_one(&*POOL)
+        .fetch_one(executor)
         .await?;
         Ok(entry)
     }
 
+    /// The entry's version, or `None` if it does not exist.
+    pub async fn fetch_version<'e, E>(
+        executor: E,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<Option<i64>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let version = sqlx::query_scalar!(
+            r#"
+              select
+                version
+              from
+                entries
+              where
+               namespace = $1
+               and
+               key_name = $2
+            "#,
+            namespace,
+            key_name
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(version)
+    }
+
     #[allow(dead_code)]
-    pub async fn fetch_by_id(id: i64) -> Result<Self, CacheVaultError> {
+    pub async fn fetch_by_id<'e, E>(executor: E, id: i64) -> Result<Self, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
         let entry = sqlx::query_as!(
             Entry,
             r#"
@@ -74,6 +210,12 @@ impl Entry {
               , key_name
               , nonce
               , encrypted_value
+              , key_id
+              , format_version
+              , algorithm
+              , content_type
+              , sliding_ttl_millis
+              , version
               , created_at
               , updated_at
               , expired_at
@@ -84,59 +226,566 @@ impl Entry {
             "#,
             id
         )
-        .fetch_one(&*POOL)
+        .fetch_one(executor)
         .await?;
         Ok(entry)
     }
 
-    // pub async fn search_by_attributes(namespace: &str, attributes: HashMap<String, String>) -> Result<Self, CacheVaultError> {
-    //     todo!()
-    // }
+    /// Entries in `namespace` with any of `key_names`, in one query.
+    pub async fn fetch_many<'e, E>(
+        executor: E,
+        namespace: &str,
+        key_names: &[&str],
+    ) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        if key_names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut query = QueryBuilder::<Sqlite>::new(
+            r#"
+              select
+                id
+              , namespace
+              , key_name
+              , nonce
+              , encrypted_value
+              , key_id
+              , format_version
+              , algorithm
+              , content_type
+              , sliding_ttl_millis
+              , version
+              , created_at
+              , updated_at
+              , expired_at
+              from
+                entries
+              where
+                namespace = "#,
+        );
+        query.push_bind(namespace);
+        query.push(" and key_name in (");
+        let mut separated = query.separated(", ");
+        for key_name in key_names {
+            separated.push_bind(*key_name);
+        }
+        query.push(")");
+        let entries = query
+            .build_query_as::<Entry>()
+            .fetch_all(executor)
+            .await
+            .with_context(|| format!("failed to fetch entries namespace={:?}", namespace))?;
+        Ok(entries)
+    }
+
+    /// Every entry, expired or not, ordered by namespace and key name.
+    pub async fn fetch_all<'e, E>(executor: E) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let entries = sqlx::query_as!(
+            Entry,
+            r#"
+              select
+                id
+              , namespace
+              , key_name
+              , nonce
+              , encrypted_value
+              , key_id
+              , format_version
+              , algorithm
+              , content_type
+              , sliding_ttl_millis
+              , version
+              , created_at
+              , updated_at
+              , expired_at
+              from
+                entries
+              order by
+                namespace
+              , key_name
+            "#
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(entries)
+    }
+
+    /// Entries not encrypted with their namespace's data key or not in the current format, oldest first.
+    pub async fn fetch_outdated<'e, E>(executor: E, limit: i64) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let entries = sqlx::query_as!(
+            Entry,
+            r#"
+              select
+                e.id
+              , e.namespace
+              , e.key_name
+              , e.nonce
+              , e.encrypted_value
+              , e.key_id
+              , e.format_version
+              , e.algorithm
+              , e.content_type
+              , e.sliding_ttl_millis
+              , e.version
+              , e.created_at
+              , e.updated_at
+              , e.expired_at
+              from
+                entries e
+                left join namespace_keys nk on nk.namespace = e.namespace
+              where
+                nk.data_key_id is null
+                or
+                e.key_id is null
+                or
+                e.key_id != nk.data_key_id
+                or
+                e.format_version < $1
+              order by
+                e.id
+              limit $2
+            "#,
+            FORMAT_VERSION,
+            limit
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(entries)
+    }
+
+    /// Metadata of the entries in `namespace` matching `options`; `now` decides what has expired.
+    pub async fn list<'e, E>(
+        executor: E,
+        namespace: &str,
+        options: &ListOptions,
+        now: NaiveDateTime,
+    ) -> Result<Vec<EntryInfo>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "select key_name, created_at, updated_at, expired_at from entries where namespace = ",
+        );
+        query.push_bind(namespace);
+        if let Some(prefix) = &options.prefix {
+            query.push(" and substr(key_name, 1, length(");
+            query.push_bind(prefix);
+            query.push(")) = ");
+            query.push_bind(prefix);
+        }
+        if !options.include_expired {
+            query.push(" and (expired_at is null or expired_at > ");
+            query.push_bind(now);
+            query.push(")");
+        }
+        let comparison = if options.descending { " < " } else { " > " };
+        if let Some(after) = &options.after {
+            match options.order {
+                ListOrder::KeyName => {
+                    query.push(" and key_name");
+                    query.push(comparison);
+                    query.push_bind(after.key_name.clone());
+                }
+                ListOrder::CreatedAt | ListOrder::UpdatedAt => {
+                    let value = match options.order {
+                        ListOrder::CreatedAt => after.created_at,
+                        _ => after.updated_at,
+                    };
+                    query.push(format_args!(" and ({}, key_name)", options.order.column()));
+                    query.push(comparison);
+                    query.push("(");
+                    query.push_bind(value);
+                    query.push(", ");
+                    query.push_bind(after.key_name.clone());
+                    query.push(")");
+                }
+            }
+        }
+        let direction = if options.descending { "desc" } else { "asc" };
+        match options.order {
+            ListOrder::KeyName => query.push(format_args!(" order by key_name {}", direction)),
+            order => query.push(format_args!(
+                " order by {} {}, key_name {}",
+                order.column(),
+                direction,
+                direction
+            )),
+        };
+        // SQLite requires a limit for an offset; -1 means no limit.
+        query.push(" limit ");
+        query.push_bind(options.limit.unwrap_or(-1));
+        query.push(" offset ");
+        query.push_bind(options.offset.unwrap_or(0));
+
+        let entries = query
+            .build_query_as::<EntryInfo>()
+            .fetch_all(executor)
+            .await
+            .with_context(|| format!("failed to list entries namespace={:?}", namespace))?;
+        Ok(entries)
+    }
+
+    pub async fn namespaces<'e, E>(executor: E) -> Result<Vec<String>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let namespaces = sqlx::query_scalar!(
+            r#"
+              select distinct
+                namespace
+              from
+                entries
+              order by
+                namespace
+            "#
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(namespaces)
+    }
+
+    /// Replaces the ciphertext of entry `id` unless it was rewritten since `old_nonce` was read.
+    pub async fn update_ciphertext<'e, E>(
+        executor: E,
+        id: i64,
+        old_nonce: &[u8],
+        nonce: &[u8],
+        encrypted_value: &[u8],
+        key_id: &str,
+        algorithm: Algorithm,
+    ) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let algorithm = algorithm.as_str();
+        let result = sqlx::query!(
+            r#"
+              update entries set
+                nonce = $3
+              , encrypted_value = $4
+              , key_id = $5
+              , format_version = $6
+              , algorithm = $7
+              where
+                id = $1
+                and
+                nonce = $2
+            "#,
+            id,
+            old_nonce,
+            nonce,
+            encrypted_value,
+            key_id,
+            FORMAT_VERSION,
+            algorithm
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to update entries id={:?}", id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Moves `expired_at` of entry `id` forward to `expired_at` if the entry is still at `version`,
+    /// so the expiration of a newer save is kept. Returns whether it was moved.
+    pub async fn extend_expiration<'e, E>(
+        executor: E,
+        id: i64,
+        version: i64,
+        expired_at: NaiveDateTime,
+    ) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              update entries set
+                expired_at = $3
+              where
+                id = $1
+                and version = $2
+            "#,
+            id,
+            version,
+            expired_at
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to extend expiration of entries id={:?}", id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Deletes entries whose `expired_at` is at or before `now`, returning how many were deleted.
+    ///
+    /// Their attributes and kept versions are deleted with them by `on delete cascade`.
+    pub async fn delete_expired<'e, E>(executor: E, now: NaiveDateTime) -> Result<u64, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              delete from entries
+              where
+                expired_at <= $1
+            "#,
+            now
+        )
+        .execute(executor)
+        .await
+        .context("failed to delete expired entries")?;
+        Ok(result.rows_affected())
+    }
+
+    /// Unexpired entries in `namespace` having every `(name, hashed_value)` attribute in `attributes`.
+    pub async fn search_by_attributes<'e, E>(
+        executor: E,
+        namespace: &str,
+        attributes: &[(&str, [u8; 32])],
+        now: NaiveDateTime,
+    ) -> Result<Vec<EntryInfo>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "select key_name, created_at, updated_at, expired_at from entries where namespace = ",
+        );
+        query.push_bind(namespace);
+        query.push(" and (expired_at is null or expired_at > ");
+        query.push_bind(now);
+        query.push(")");
+        for (name, hashed_value) in attributes {
+            query.push(" and id in (select entry_id from attributes where name = ");
+            query.push_bind(*name);
+            query.push(" and hashed_value = ");
+            query.push_bind(hashed_value.to_vec());
+            query.push(")");
+        }
+        query.push(" order by key_name");
+
+        let entries = query
+            .build_query_as::<EntryInfo>()
+            .fetch_all(executor)
+            .await
+            .with_context(|| format!("failed to search entries namespace={:?}", namespace))?;
+        Ok(entries)
+    }
 
-    pub async fn upsert(
+    /// Inserts or overwrites the entry, returning its id.
+    ///
+    /// With `expected_version`, an existing entry is only overwritten if it is at that version;
+    /// otherwise no row is returned and this fails with `RowNotFound`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upsert<'e, E>(
+        executor: E,
+        keys: &dyn KeyProvider,
+        algorithm: Algorithm,
         namespace: &str,
         key_name: &str,
-        value: &str,
+        value: &[u8],
+        content_type: Option<&str>,
         expired_at: Option<NaiveDateTime>,
-    ) -> Result<i64, CacheVaultError> {
-        let (encrypted_value, nonce) = encrypt(value.to_string())?;
-        let id = sqlx::query!(
+        sliding_ttl_millis: Option<i64>,
+        expected_version: Option<i64>,
+    ) -> Result<(i64, i64), CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let key = keys.encryption_key()?;
+        let key_id = key_id(&key)?;
+        let aad = Self::associated_data(namespace, key_name);
+        let (encrypted_value, nonce) = encrypt_bytes(algorithm, &key, &aad, value)?;
+        let algorithm = algorithm.as_str();
+        let rows = sqlx::query!(
             r#"
               insert into
-                entries(namespace, key_name, nonce, encrypted_value, created_at, updated_at, expired_at)
-                values ($1, $2, $3, $4, datetime('now'), datetime('now'), $5)
+                entries(namespace, key_name, nonce, encrypted_value, key_id, format_version, algorithm, content_type, created_at, updated_at, expired_at, sliding_ttl_millis)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, datetime('now'), datetime('now'), $9, $10)
                 on conflict (namespace, key_name) do update set
                   nonce = $3
                 , encrypted_value = $4
+                , key_id = $5
+                , format_version = $6
+                , algorithm = $7
+                , content_type = $8
                 , updated_at = datetime('now')
-                , expired_at = $5
+                , expired_at = $9
+                , sliding_ttl_millis = $10
+                , version = version + 1
+                where
+                  $11 is null
+                  or
+                  version = $11
+              returning id, version
             "#,
             namespace,
             key_name,
             nonce,
             encrypted_value,
+            key_id,
+            FORMAT_VERSION,
+            algorithm,
+            content_type,
             expired_at,
+            sliding_ttl_millis,
+            expected_version,
         )
-        .execute(&*POOL)
+        // `fetch_one` can return before the statement has finished and its implicit transaction committed.
+        .fetch_all(executor)
         .await
         .with_context(|| {
             format!(
                 "failed to upsert entries namespace={:?}, key_name={:?}",
                 namespace, key_name
             )
-        })?
-        .last_insert_rowid();
-        Ok(id)
+        })?;
+        let row = rows
+            .into_iter()
+            .next()
+            .ok_or(CacheVaultError::SqlxError(sqlx::Error::RowNotFound))?;
+        Ok((row.id, row.version))
+    }
+
+    /// Makes the archived `entry_version` the current value of its entry, as a new version.
+    pub async fn restore<'e, E>(executor: E, entry_version: &EntryVersion) -> Result<(), CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        sqlx::query!(
+            r#"
+              update entries set
+                nonce = $2
+              , encrypted_value = $3
+              , key_id = $4
+              , format_version = $5
+              , algorithm = $6
+              , content_type = $7
+              , version = version + 1
+              , updated_at = datetime('now')
+              where
+                id = $1
+            "#,
+            entry_version.entry_id,
+            entry_version.nonce,
+            entry_version.encrypted_value,
+            entry_version.key_id,
+            entry_version.format_version,
+            entry_version.algorithm,
+            entry_version.content_type
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to restore entries id={:?}", entry_version.entry_id))?;
+        Ok(())
+    }
+
+    /// Sets the creation and update times of entry `id`, e.g. to those of a restored backup.
+    pub async fn set_timestamps<'e, E>(
+        executor: E,
+        id: i64,
+        created_at: NaiveDateTime,
+        updated_at: NaiveDateTime,
+    ) -> Result<(), CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        sqlx::query!(
+            r#"
+              update entries set
+                created_at = $2
+              , updated_at = $3
+              where
+                id = $1
+            "#,
+            id,
+            created_at,
+            updated_at
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to set timestamps of entries id={:?}", id))?;
+        Ok(())
+    }
+
+    /// Deletes the entry and, through `on delete cascade`, its attributes.
+    pub async fn delete<'e, E>(executor: E, namespace: &str, key_name: &str) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              delete from entries
+              where
+                namespace = $1
+                and
+                key_name = $2
+            "#,
+            namespace,
+            key_name
+        )
+        .execute(executor)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to delete entries namespace={:?}, key_name={:?}",
+                namespace, key_name
+            )
+        })?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Deletes every entry in `namespace`, returning how many were deleted.
+    pub async fn delete_namespace<'e, E>(executor: E, namespace: &str) -> Result<u64, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              delete from entries
+              where
+                namespace = $1
+            "#,
+            namespace
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to delete entries namespace={:?}", namespace))?;
+        Ok(result.rows_affected())
     }
 }
 
 impl Attribute {
-    pub fn plaintext(&self) -> Result<String, CacheVaultError> {
-        decrypt(&self.nonce, &self.encrypted_value)
+    pub fn associated_data(entry_id: i64, name: &str) -> Vec<u8> {
+        associated_data(&[b"attributes", &entry_id.to_le_bytes(), name.as_bytes()])
+    }
+
+    pub fn plaintext(&self, keys: &dyn KeyProvider) -> Result<String, CacheVaultError> {
+        let aad = match self.format_version {
+            1 => Vec::new(),
+            _ => Self::associated_data(self.entry_id, &self.name),
+        };
+        decrypt_row(
+            keys,
+            self.key_id.as_deref(),
+            self.algorithm.parse()?,
+            &aad,
+            &self.nonce,
+            &self.encrypted_value,
+            decrypt,
+        )
     }
 
     #[allow(dead_code)]
-    pub async fn fetch_by_id(id: i64) -> Result<Self, CacheVaultError> {
+    pub async fn fetch_by_id<'e, E>(executor: E, id: i64) -> Result<Self, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
         let attribute = sqlx::query_as!(
             Attribute,
             r#"
@@ -147,6 +796,9 @@ impl Attribute {
               , nonce
               , encrypted_value
               , hashed_value
+              , key_id
+              , format_version
+              , algorithm
               , created_at
               , updated_at
               from
@@ -156,13 +808,16 @@ impl Attribute {
             "#,
             id
         )
-        .fetch_one(&*POOL)
+        .fetch_one(executor)
         .await?;
         Ok(attribute)
     }
 
     #[allow(dead_code)]
-    pub async fn fetch_by_name(entry_id: i64, name: &str) -> Result<Self, CacheVaultError> {
+    pub async fn fetch_by_name<'e, E>(executor: E, entry_id: i64, name: &str) -> Result<Self, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
         let attribute = sqlx::query_as!(
             Attribute,
             r#"
@@ -173,6 +828,9 @@ impl Attribute {
               , nonce
               , encrypted_value
               , hashed_value
+              , key_id
+              , format_version
+              , algorithm
               , created_at
               , updated_at
               from
@@ -185,12 +843,15 @@ impl Attribute {
             entry_id,
             name
         )
-        .fetch_one(&*POOL)
+        .fetch_one(executor)
         .await?;
         Ok(attribute)
     }
 
-    pub async fn fetch_all(entry_id: i64) -> Result<Vec<Self>, CacheVaultError> {
+    pub async fn fetch_all<'e, E>(executor: E, entry_id: i64) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
         let attributes = sqlx::query_as!(
             Attribute,
             r#"
@@ -201,6 +862,9 @@ impl Attribute {
               , nonce
               , encrypted_value
               , hashed_value
+              , key_id
+              , format_version
+              , algorithm
               , created_at
               , updated_at
               from
@@ -212,48 +876,908 @@ impl Attribute {
             "#,
             entry_id
         )
-        .fetch_all(&*POOL)
+        .fetch_all(executor)
         .await?;
         Ok(attributes)
     }
 
-    pub async fn upsert(entry_id: i64, name: &str, value: &str) -> Result<i64, CacheVaultError> {
-        let (encrypted_value, nonce) = encrypt(value.to_string())?;
-        let hashed_value = digest(value.as_bytes())?.to_vec();
-        let id = sqlx::query!(
+    pub async fn upsert<'e, E>(
+        executor: E,
+        keys: &dyn KeyProvider,
+        algorithm: Algorithm,
+        entry_id: i64,
+        name: &str,
+        value: &str,
+    ) -> Result<i64, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let key = keys.encryption_key()?;
+        let key_id = key_id(&key)?;
+        let aad = Self::associated_data(entry_id, name);
+        let (encrypted_value, nonce) = encrypt(algorithm, &key, &aad, value.to_string())?;
+        let algorithm = algorithm.as_str();
+        let hashed_value = digest(&keys.pepper()?, value.as_bytes())?.to_vec();
+        let ids = sqlx::query_scalar!(
             r#"
               insert into
-                attributes (entry_id, name, nonce, encrypted_value, hashed_value, created_at, updated_at)
-                values ($1, $2, $3, $4, $5, datetime('now'), datetime('now'))
+                attributes (entry_id, name, nonce, encrypted_value, hashed_value, key_id, format_version, algorithm, created_at, updated_at)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, datetime('now'), datetime('now'))
                 on conflict(entry_id, name) do update set
                   nonce = $3
                 , encrypted_value = $4
                 , hashed_value = $5
+                , key_id = $6
+                , format_version = $7
+                , algorithm = $8
                 , updated_at = datetime('now')
+              returning id
             "#,
             entry_id,
             name,
             nonce,
             encrypted_value,
-            hashed_value
+            hashed_value,
+            key_id,
+            FORMAT_VERSION,
+            algorithm
+        )
+        .fetch_all(executor)
+        .await
+        .with_context(|| format!("failed to upsert attributes entry_id={:?} name={:?}", entry_id, name))?;
+        first_id(ids)
+    }
+
+    /// Attributes not encrypted with their namespace's data key or not in the current format, oldest first.
+    pub async fn fetch_outdated<'e, E>(executor: E, limit: i64) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let attributes = sqlx::query_as!(
+            Attribute,
+            r#"
+              select
+                a.id
+              , a.entry_id
+              , a.name
+              , a.nonce
+              , a.encrypted_value
+              , a.hashed_value
+              , a.key_id
+              , a.format_version
+              , a.algorithm
+              , a.created_at
+              , a.updated_at
+              from
+                attributes a
+                inner join entries e on e.id = a.entry_id
+                left join namespace_keys nk on nk.namespace = e.namespace
+              where
+                nk.data_key_id is null
+                or
+                a.key_id is null
+                or
+                a.key_id != nk.data_key_id
+                or
+                a.format_version < $1
+              order by
+                a.id
+              limit $2
+            "#,
+            FORMAT_VERSION,
+            limit
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(attributes)
+    }
+
+    /// Replaces the ciphertext of attribute `id` unless it was rewritten since `old_nonce` was read.
+    pub async fn update_ciphertext<'e, E>(
+        executor: E,
+        id: i64,
+        old_nonce: &[u8],
+        nonce: &[u8],
+        encrypted_value: &[u8],
+        key_id: &str,
+        algorithm: Algorithm,
+    ) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let algorithm = algorithm.as_str();
+        let result = sqlx::query!(
+            r#"
+              update attributes set
+                nonce = $3
+              , encrypted_value = $4
+              , key_id = $5
+              , format_version = $6
+              , algorithm = $7
+              where
+                id = $1
+                and
+                nonce = $2
+            "#,
+            id,
+            old_nonce,
+            nonce,
+            encrypted_value,
+            key_id,
+            FORMAT_VERSION,
+            algorithm
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to update attributes id={:?}", id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Deletes the attributes of entry `entry_id` whose names are not in `names`.
+    pub async fn delete_except<'e, E>(executor: E, entry_id: i64, names: &[&str]) -> Result<u64, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let mut query = QueryBuilder::<Sqlite>::new("delete from attributes where entry_id = ");
+        query.push_bind(entry_id);
+        if !names.is_empty() {
+            query.push(" and name not in (");
+            let mut separated = query.separated(", ");
+            for name in names {
+                separated.push_bind(*name);
+            }
+            query.push(")");
+        }
+        let result = query
+            .build()
+            .execute(executor)
+            .await
+            .with_context(|| format!("failed to delete attributes entry_id={:?}", entry_id))?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_by_name<'e, E>(executor: E, entry_id: i64, name: &str) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              delete from attributes
+              where
+                entry_id = $1
+                and
+                name = $2
+            "#,
+            entry_id,
+            name
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to delete attributes entry_id={:?} name={:?}", entry_id, name))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete<'e, E>(
+        executor: E,
+        namespace: &str,
+        key_name: &str,
+        name: &str,
+    ) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              delete from attributes
+              where
+                entry_id = (select id from entries where namespace = $1 and key_name = $2)
+                and
+                name = $3
+            "#,
+            namespace,
+            key_name,
+            name
+        )
+        .execute(executor)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to delete attributes namespace={:?}, key_name={:?}, name={:?}",
+                namespace, key_name, name
+            )
+        })?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+impl NamespaceKey {
+    pub fn associated_data(namespace: &str) -> Vec<u8> {
+        associated_data(&[b"namespace_keys", namespace.as_bytes()])
+    }
+
+    /// Unwraps the data encryption key with the master keys from `keys`.
+    pub fn data_key(&self, keys: &dyn KeyProvider) -> Result<Vec<u8>, CacheVaultError> {
+        let aad = Self::associated_data(&self.namespace);
+        let mut result = Err(CacheVaultError::UnknownKeyId(self.key_id.clone()));
+        for key in candidate_keys(keys, Some(&self.key_id))? {
+            result = decrypt_bytes(Algorithm::ChaCha20Poly1305, &key, &aad, &self.nonce, &self.wrapped_key);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    pub async fn fetch_optional<'e, E>(executor: E, namespace: &str) -> Result<Option<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let namespace_key = sqlx::query_as!(
+            NamespaceKey,
+            r#"
+              select
+                id
+              , namespace
+              , nonce
+              , wrapped_key
+              , key_id
+              , data_key_id
+              , created_at
+              , updated_at
+              from
+                namespace_keys
+              where
+                namespace = $1
+            "#,
+            namespace
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(namespace_key)
+    }
+
+    /// Namespace keys not wrapped with master key `key_id`, oldest first.
+    pub async fn fetch_outdated<'e, E>(executor: E, key_id: &str, limit: i64) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let namespace_keys = sqlx::query_as!(
+            NamespaceKey,
+            r#"
+              select
+                id
+              , namespace
+              , nonce
+              , wrapped_key
+              , key_id
+              , data_key_id
+              , created_at
+              , updated_at
+              from
+                namespace_keys
+              where
+                key_id != $1
+              order by
+                id
+              limit $2
+            "#,
+            key_id,
+            limit
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(namespace_keys)
+    }
+
+    /// Generates and stores a data encryption key for `namespace` unless another writer already did.
+    pub async fn insert<'e, E>(executor: E, keys: &dyn KeyProvider, namespace: &str) -> Result<(), CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let key = keys.encryption_key()?;
+        let data_key = generate_key_bytes();
+        let data_key_id = key_id(&data_key)?;
+        let key_id = key_id(&key)?;
+        let (wrapped_key, nonce) = encrypt_bytes(
+            Algorithm::ChaCha20Poly1305,
+            &key,
+            &Self::associated_data(namespace),
+            &data_key,
+        )?;
+        sqlx::query!(
+            r#"
+              insert into
+                namespace_keys (namespace, nonce, wrapped_key, key_id, data_key_id, created_at, updated_at)
+                values ($1, $2, $3, $4, $5, datetime('now'), datetime('now'))
+                on conflict (namespace) do nothing
+            "#,
+            namespace,
+            nonce,
+            wrapped_key,
+            key_id,
+            data_key_id
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to insert namespace_keys namespace={:?}", namespace))?;
+        Ok(())
+    }
+
+    /// Re-wraps the data encryption key with the current master key unless the row changed since
+    /// it was read.
+    pub async fn rewrap<'e, E>(&self, executor: E, keys: &dyn KeyProvider) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let data_key = self.data_key(keys)?;
+        let key = keys.encryption_key()?;
+        let key_id = key_id(&key)?;
+        let (wrapped_key, nonce) = encrypt_bytes(
+            Algorithm::ChaCha20Poly1305,
+            &key,
+            &Self::associated_data(&self.namespace),
+            &data_key,
+        )?;
+        let result = sqlx::query!(
+            r#"
+              update namespace_keys set
+                nonce = $3
+              , wrapped_key = $4
+              , key_id = $5
+              , updated_at = datetime('now')
+              where
+                id = $1
+                and
+                nonce = $2
+            "#,
+            self.id,
+            self.nonce,
+            nonce,
+            wrapped_key,
+            key_id
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to update namespace_keys id={:?}", self.id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete<'e, E>(executor: E, namespace: &str) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              delete from namespace_keys
+              where
+                namespace = $1
+            "#,
+            namespace
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to delete namespace_keys namespace={:?}", namespace))?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+impl EntryVersion {
+    /// Decrypts the archived value of the entry `namespace`/`key_name`.
+    pub fn plaintext_bytes(
+        &self,
+        keys: &dyn KeyProvider,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<Vec<u8>, CacheVaultError> {
+        let aad = match self.format_version {
+            1 => Vec::new(),
+            _ => Entry::associated_data(namespace, key_name),
+        };
+        decrypt_row(
+            keys,
+            self.key_id.as_deref(),
+            self.algorithm.parse()?,
+            &aad,
+            &self.nonce,
+            &self.encrypted_value,
+            decrypt_bytes,
+        )
+    }
+
+    /// Copies the current value of the entry `namespace`/`key_name`, if any, into the history.
+    /// Skipped unless the entry is at `expected_version`, if given.
+    pub async fn archive<'e, E>(
+        executor: E,
+        namespace: &str,
+        key_name: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        sqlx::query!(
+            r#"
+              insert into
+                entry_versions (entry_id, version, nonce, encrypted_value, key_id, format_version, algorithm, content_type, created_at, archived_at)
+                select
+                  id
+                , version
+                , nonce
+                , encrypted_value
+                , key_id
+                , format_version
+                , algorithm
+                , content_type
+                , updated_at
+                , datetime('now')
+                from
+                  entries
+                where
+                  namespace = $1
+                  and
+                  key_name = $2
+                  and
+                  ($3 is null or version = $3)
+                on conflict (entry_id, version) do nothing
+            "#,
+            namespace,
+            key_name,
+            expected_version
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to archive entries namespace={:?}, key_name={:?}", namespace, key_name))?;
+        Ok(())
+    }
+
+    /// Deletes all but the newest `limit` versions of every entry in `namespace`, or only of
+    /// `key_name` if given.
+    pub async fn prune<'e, E>(
+        executor: E,
+        namespace: &str,
+        key_name: Option<&str>,
+        limit: i64,
+    ) -> Result<u64, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              delete from entry_versions
+              where
+                id in (
+                  select id from (
+                    select
+                      v.id
+                    , row_number() over (partition by v.entry_id order by v.version desc) as n
+                    from
+                      entry_versions v
+                      inner join entries e on e.id = v.entry_id
+                    where
+                      e.namespace = $1
+                      and
+                      ($2 is null or e.key_name = $2)
+                  )
+                  where
+                    n > $3
+                )
+            "#,
+            namespace,
+            key_name,
+            limit
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to prune entry_versions namespace={:?}", namespace))?;
+        Ok(result.rows_affected())
+    }
+
+    /// Versions not yet encrypted with their namespace's data key in the current format, oldest
+    /// first.
+    pub async fn fetch_outdated<'e, E>(executor: E, limit: i64) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let versions = sqlx::query_as!(
+            EntryVersion,
+            r#"
+              select
+                v.id
+              , v.entry_id
+              , v.version
+              , v.nonce
+              , v.encrypted_value
+              , v.key_id
+              , v.format_version
+              , v.algorithm
+              , v.content_type
+              , v.created_at
+              , v.archived_at
+              from
+                entry_versions v
+                inner join entries e on e.id = v.entry_id
+                left join namespace_keys nk on nk.namespace = e.namespace
+              where
+                nk.data_key_id is null
+                or
+                v.key_id is null
+                or
+                v.key_id != nk.data_key_id
+                or
+                v.format_version < $1
+              order by
+                v.id
+              limit $2
+            "#,
+            FORMAT_VERSION,
+            limit
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(versions)
+    }
+
+    /// Replaces the ciphertext of version `id` unless it changed since it was read.
+    pub async fn update_ciphertext<'e, E>(
+        executor: E,
+        id: i64,
+        old_nonce: &[u8],
+        nonce: &[u8],
+        encrypted_value: &[u8],
+        key_id: &str,
+        algorithm: Algorithm,
+    ) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let algorithm = algorithm.as_str();
+        let result = sqlx::query!(
+            r#"
+              update entry_versions set
+                nonce = $3
+              , encrypted_value = $4
+              , key_id = $5
+              , format_version = $6
+              , algorithm = $7
+              where
+                id = $1
+                and
+                nonce = $2
+            "#,
+            id,
+            old_nonce,
+            nonce,
+            encrypted_value,
+            key_id,
+            FORMAT_VERSION,
+            algorithm
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to update entry_versions id={:?}", id))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Versions of entry `entry_id`, newest first.
+    pub async fn fetch_all<'e, E>(executor: E, entry_id: i64) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let versions = sqlx::query_as!(
+            EntryVersion,
+            r#"
+              select
+                id
+              , entry_id
+              , version
+              , nonce
+              , encrypted_value
+              , key_id
+              , format_version
+              , algorithm
+              , content_type
+              , created_at
+              , archived_at
+              from
+                entry_versions
+              where
+                entry_id = $1
+              order by
+                version desc
+            "#,
+            entry_id
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(versions)
+    }
+
+    pub async fn fetch<'e, E>(executor: E, entry_id: i64, version: i64) -> Result<Self, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let entry_version = sqlx::query_as!(
+            EntryVersion,
+            r#"
+              select
+                id
+              , entry_id
+              , version
+              , nonce
+              , encrypted_value
+              , key_id
+              , format_version
+              , algorithm
+              , content_type
+              , created_at
+              , archived_at
+              from
+                entry_versions
+              where
+                entry_id = $1
+                and
+                version = $2
+            "#,
+            entry_id,
+            version
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok(entry_version)
+    }
+}
+
+impl Metadata {
+    pub async fn fetch_optional<'e, E>(executor: E, name: &str) -> Result<Option<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let metadata = sqlx::query_as!(
+            Metadata,
+            r#"
+              select
+                name as "name!"
+              , value
+              , created_at
+              , updated_at
+              from
+                metadata
+              where
+                name = $1
+            "#,
+            name
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(metadata)
+    }
+
+    pub async fn upsert<'e, E>(executor: E, name: &str, value: &[u8]) -> Result<(), CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        sqlx::query!(
+            r#"
+              insert into
+                metadata (name, value, created_at, updated_at)
+                values ($1, $2, datetime('now'), datetime('now'))
+                on conflict (name) do update set
+                  value = $2
+                , updated_at = datetime('now')
+            "#,
+            name,
+            value
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to upsert metadata name={:?}", name))?;
+        Ok(())
+    }
+
+    /// Inserts `name` unless it already exists, returning whether it was inserted.
+    pub async fn insert_or_ignore<'e, E>(executor: E, name: &str, value: &[u8]) -> Result<bool, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query!(
+            r#"
+              insert into
+                metadata (name, value, created_at, updated_at)
+                values ($1, $2, datetime('now'), datetime('now'))
+                on conflict (name) do nothing
+            "#,
+            name,
+            value
         )
-        .execute(&*POOL)
+        .execute(executor)
         .await
-        .with_context(|| format!("failed to upsert attributes entry_id={:?} name={:?}", entry_id, name))?
-        .last_insert_rowid();
-        Ok(id)
+        .with_context(|| format!("failed to insert metadata name={:?}", name))?;
+        Ok(result.rows_affected() > 0)
     }
 }
 
+impl AuditLog {
+    /// Inserts a record without its hash, returning its id.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert<'e, E>(
+        executor: E,
+        operation: &str,
+        namespace: &str,
+        key_name: &str,
+        pid: i64,
+        executable: Option<&str>,
+        outcome: &str,
+        created_at: NaiveDateTime,
+    ) -> Result<i64, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let ids = sqlx::query_scalar!(
+            r#"
+              insert into
+                audit_log (operation, namespace, key_name, pid, executable, outcome, created_at)
+                values ($1, $2, $3, $4, $5, $6, $7)
+                returning id
+            "#,
+            operation,
+            namespace,
+            key_name,
+            pid,
+            executable,
+            outcome,
+            created_at
+        )
+        .fetch_all(executor)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to insert audit_log operation={:?}, namespace={:?}, key_name={:?}",
+                operation, namespace, key_name
+            )
+        })?;
+        first_id(ids)
+    }
+
+    /// Hash of the record before `id`, or `None` if `id` is the first record.
+    pub async fn previous_hash<'e, E>(executor: E, id: i64) -> Result<Option<Option<Vec<u8>>>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let hash = sqlx::query_scalar!(
+            r#"
+              select
+                hash
+              from
+                audit_log
+              where
+                id < $1
+              order by
+                id desc
+              limit 1
+            "#,
+            id
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(hash)
+    }
+
+    pub async fn update_hash<'e, E>(executor: E, id: i64, hash: &[u8]) -> Result<(), CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        sqlx::query!(
+            r#"
+              update audit_log
+              set
+                hash = $2
+              where
+                id = $1
+            "#,
+            id,
+            hash
+        )
+        .execute(executor)
+        .await
+        .with_context(|| format!("failed to update audit_log id={:?}", id))?;
+        Ok(())
+    }
+
+    pub async fn fetch_by_id<'e, E>(executor: E, id: i64) -> Result<Self, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let record = sqlx::query_as!(
+            AuditLog,
+            r#"
+              select
+                id as "id!"
+              , operation
+              , namespace
+              , key_name
+              , pid
+              , executable
+              , outcome
+              , created_at
+              , hash
+              from
+                audit_log
+              where
+                id = $1
+            "#,
+            id
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok(record)
+    }
+
+    /// All records, oldest first.
+    pub async fn fetch_all<'e, E>(executor: E) -> Result<Vec<Self>, CacheVaultError>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let records = sqlx::query_as!(
+            AuditLog,
+            r#"
+              select
+                id as "id!"
+              , operation
+              , namespace
+              , key_name
+              , pid
+              , executable
+              , outcome
+              , created_at
+              , hash
+              from
+                audit_log
+              order by
+                id
+            "#
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(records)
+    }
+}
+
+fn first_id(ids: Vec<i64>) -> Result<i64, CacheVaultError> {
+    ids.first()
+        .copied()
+        .ok_or(CacheVaultError::SqlxError(sqlx::Error::RowNotFound))
+}
+
+type Decrypt<T> = fn(Algorithm, &[u8], &[u8], &[u8], &[u8]) -> Result<T, CacheVaultError>;
+
+fn decrypt_row<T>(
+    keys: &dyn KeyProvider,
+    key_id: Option<&str>,
+    algorithm: Algorithm,
+    aad: &[u8],
+    nonce: &[u8],
+    encrypted_value: &[u8],
+    decrypt: Decrypt<T>,
+) -> Result<T, CacheVaultError> {
+    let mut result = Err(CacheVaultError::UnknownKeyId(key_id.unwrap_or_default().to_string()));
+    for key in candidate_keys(keys, key_id)? {
+        result = decrypt(algorithm, &key, aad, nonce, encrypted_value);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::connection::migrate;
+    use crate::vault::tests::open_test_vault;
 
     #[tokio::test]
     async fn test_entry_fetch_no_such_key() -> Result<(), CacheVaultError> {
-        let _ = migrate().await?;
-        match Entry::fetch("test", "no-such-key").await {
+        let (vault, _dir) = open_test_vault().await?;
+        match Entry::fetch(vault.pool(), "test", "no-such-key").await {
             Err(e) => match e {
                 CacheVaultError::SqlxError(sqlx::Error::RowNotFound) => (),
                 _ => panic!("unexpected"),
@@ -265,15 +1789,30 @@ mod tests {
 
     #[tokio::test]
     async fn test_attribute_fetch_all() -> Result<(), CacheVaultError> {
-        let _ = migrate().await?;
-        let entry_id = Entry::upsert("test", "test-key", "test-value", None).await?;
-
-        let (envrypted_value0, nonce0) = encrypt(String::from("value0"))?;
-        let (envrypted_value1, nonce1) = encrypt(String::from("value1"))?;
-        let (envrypted_value2, nonce2) = encrypt(String::from("value2"))?;
-        let hashed_value0 = digest(b"value0")?.to_vec();
-        let hashed_value1 = digest(b"value1")?.to_vec();
-        let hashed_value2 = digest(b"value2")?.to_vec();
+        let (vault, _dir) = open_test_vault().await?;
+        let (pool, keys) = (vault.pool(), vault.keys());
+        let (entry_id, _) = Entry::upsert(
+            pool,
+            keys,
+            Algorithm::default(),
+            "test",
+            "test-key",
+            b"test-value",
+            None,
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+        let key = keys.encryption_key()?;
+        let pepper = keys.pepper()?;
+        let (envrypted_value0, nonce0) = encrypt(Algorithm::default(), &key, b"", String::from("value0"))?;
+        let (envrypted_value1, nonce1) = encrypt(Algorithm::default(), &key, b"", String::from("value1"))?;
+        let (envrypted_value2, nonce2) = encrypt(Algorithm::default(), &key, b"", String::from("value2"))?;
+        let hashed_value0 = digest(&pepper, b"value0")?.to_vec();
+        let hashed_value1 = digest(&pepper, b"value1")?.to_vec();
+        let hashed_value2 = digest(&pepper, b"value2")?.to_vec();
         let _ = sqlx::query(
             r#"
               insert into
@@ -293,26 +1832,26 @@ mod tests {
         .bind(nonce2)
         .bind(envrypted_value2)
         .bind(hashed_value2)
-        .execute(&*POOL)
+        .execute(pool)
         .await?;
 
-        let attributes = Attribute::fetch_all(entry_id).await?;
+        let attributes = Attribute::fetch_all(pool, entry_id).await?;
         assert_eq!(attributes.len(), 3);
-        if let Some(a) = attributes.get(0) {
+        if let Some(a) = attributes.first() {
             assert_eq!(a.name, "name0");
-            assert_eq!(a.plaintext()?, "value0");
+            assert_eq!(a.plaintext(keys)?, "value0");
         } else {
             panic!("failed to fetch attribute 0");
         }
         if let Some(a) = attributes.get(1) {
             assert_eq!(a.name, "name1");
-            assert_eq!(a.plaintext()?, "value1");
+            assert_eq!(a.plaintext(keys)?, "value1");
         } else {
             panic!("failed to fetch attribute 1");
         }
         if let Some(a) = attributes.get(2) {
             assert_eq!(a.name, "name2");
-            assert_eq!(a.plaintext()?, "value2");
+            assert_eq!(a.plaintext(keys)?, "value2");
         } else {
             panic!("failed to fetch attribute 2");
         }
@@ -321,40 +1860,83 @@ mod tests {
 
     #[tokio::test]
     async fn test_upsert_entry_and_attribute() -> Result<(), CacheVaultError> {
-        let _ = migrate().await?;
-        let entry_id = Entry::upsert("test", "test-key", "test-value", None).await?;
-        let e = Entry::fetch("test", "test-key").await?;
+        let (vault, _dir) = open_test_vault().await?;
+        let (pool, keys) = (vault.pool(), vault.keys());
+        let (entry_id, version) = Entry::upsert(
+            pool,
+            keys,
+            Algorithm::default(),
+            "test",
+            "test-key",
+            b"test-value",
+            None,
+            None,
+            None,
+            None,
+        )
+        .await?;
+        let e = Entry::fetch(pool, "test", "test-key").await?;
         assert_eq!(entry_id, e.id);
+        assert_eq!(version, 1);
         assert_eq!(e.namespace, "test");
         assert_eq!(e.key_name, "test-key");
-        assert_eq!(e.plaintext()?, "test-value");
-        let entry_id2 = Entry::upsert("test", "test-key", "test-updated-value", None).await?;
-        let e = Entry::fetch_by_id(entry_id2).await?;
+        assert_eq!(e.plaintext(keys)?, "test-value");
+        let (entry_id2, version2) = Entry::upsert(
+            pool,
+            keys,
+            Algorithm::default(),
+            "test",
+            "test-key",
+            b"test-updated-value",
+            None,
+            None,
+            None,
+            None,
+        )
+        .await?;
+        let e = Entry::fetch_by_id(pool, entry_id2).await?;
         assert_eq!(entry_id, entry_id2);
+        assert_eq!(version2, 2);
         assert_eq!(entry_id, e.id);
         assert_eq!(e.namespace, "test");
         assert_eq!(e.key_name, "test-key");
-        assert_eq!(e.plaintext()?, "test-updated-value");
+        assert_eq!(e.plaintext(keys)?, "test-updated-value");
 
-        let attribute_id = Attribute::upsert(entry_id, "test-attribute", "test-attribute-value").await?;
-        let a = Attribute::fetch_by_id(attribute_id).await?;
+        let attribute_id = Attribute::upsert(
+            pool,
+            keys,
+            Algorithm::default(),
+            entry_id,
+            "test-attribute",
+            "test-attribute-value",
+        )
+        .await?;
+        let a = Attribute::fetch_by_id(pool, attribute_id).await?;
         assert_eq!(a.id, attribute_id);
         assert_eq!(a.entry_id, entry_id);
         assert_eq!(a.name, "test-attribute");
-        assert_eq!(a.plaintext()?, "test-attribute-value");
-        let attribute_id2 = Attribute::upsert(entry_id, "test-attribute", "test-updated-attribute-value").await?;
-        let a = Attribute::fetch_by_id(attribute_id).await?;
+        assert_eq!(a.plaintext(keys)?, "test-attribute-value");
+        let attribute_id2 = Attribute::upsert(
+            pool,
+            keys,
+            Algorithm::default(),
+            entry_id,
+            "test-attribute",
+            "test-updated-attribute-value",
+        )
+        .await?;
+        let a = Attribute::fetch_by_id(pool, attribute_id).await?;
         assert_eq!(attribute_id, attribute_id2);
         assert_eq!(a.id, attribute_id);
         assert_eq!(a.entry_id, entry_id);
         assert_eq!(a.name, "test-attribute");
-        assert_eq!(a.plaintext()?, "test-updated-attribute-value");
+        assert_eq!(a.plaintext(keys)?, "test-updated-attribute-value");
 
-        let a = Attribute::fetch_by_name(entry_id, "test-attribute").await?;
+        let a = Attribute::fetch_by_name(pool, entry_id, "test-attribute").await?;
         assert_eq!(a.id, attribute_id);
         assert_eq!(a.entry_id, entry_id);
         assert_eq!(a.name, "test-attribute");
-        assert_eq!(a.plaintext()?, "test-updated-attribute-value");
+        assert_eq!(a.plaintext(keys)?, "test-updated-attribute-value");
         Ok(())
     }
 }
@@ -0,0 +1,130 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use sqlx::SqlitePool;
+
+use crate::error::CacheVaultError;
+use crate::key::{KeyProvider, StaticKeyProvider};
+use crate::models::Metadata;
+
+const SALT: &str = "passphrase.salt";
+const M_COST: &str = "passphrase.m_cost";
+const T_COST: &str = "passphrase.t_cost";
+const P_COST: &str = "passphrase.p_cost";
+const NONCE: &str = "passphrase.nonce";
+const WRAPPED_KEYS: &str = "passphrase.wrapped_keys";
+
+// Binds the wrapped blob to its purpose so it cannot be confused with an entry ciphertext.
+const AAD: &[u8] = b"cache-vault passphrase-wrapped keys v1";
+
+/// Unwraps the master key and pepper stored in `metadata` with a key derived from `passphrase`.
+///
+/// On the first call for a database a fresh master key and pepper are generated, wrapped and stored.
+pub async fn unlock(pool: &SqlitePool, passphrase: &str) -> Result<StaticKeyProvider, CacheVaultError> {
+    if !is_initialized(pool).await? {
+        if let Some(provider) = initialize(pool, passphrase).await? {
+            return Ok(provider);
+        }
+    }
+    let wrapped_keys = fetch_required(pool, WRAPPED_KEYS).await?;
+    let salt = fetch_required(pool, SALT).await?;
+    let params = Params::new(
+        fetch_u32(pool, M_COST).await?,
+        fetch_u32(pool, T_COST).await?,
+        fetch_u32(pool, P_COST).await?,
+        Some(32),
+    )?;
+    let nonce = fetch_required(pool, NONCE).await?;
+    let kek = derive(passphrase, &salt, params)?;
+    let keys = unwrap(&kek, &nonce, &wrapped_keys)?;
+    let (encryption_key, pepper) = keys.split_at(32);
+    StaticKeyProvider::new(encryption_key.to_vec(), pepper.to_vec())
+}
+
+/// Returns whether the master key of the database behind `pool` is wrapped with a passphrase.
+pub(crate) async fn is_initialized(pool: &SqlitePool) -> Result<bool, CacheVaultError> {
+    Ok(fetch(pool, WRAPPED_KEYS).await?.is_some())
+}
+
+/// Generates and stores wrapped keys, or returns `None` if another process stored them first.
+async fn initialize(pool: &SqlitePool, passphrase: &str) -> Result<Option<StaticKeyProvider>, CacheVaultError> {
+    let provider = StaticKeyProvider::generate();
+    let keys = [provider.encryption_key()?, provider.pepper()?].concat();
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let params = Params::default();
+    let kek = derive(passphrase, &salt, params.clone())?;
+    let (nonce, wrapped_keys) = wrap(&kek, &keys)?;
+
+    let mut tx = pool.begin().await?;
+    let mut inserted = true;
+    for (name, value) in [
+        (SALT, &salt[..]),
+        (M_COST, &params.m_cost().to_le_bytes()),
+        (T_COST, &params.t_cost().to_le_bytes()),
+        (P_COST, &params.p_cost().to_le_bytes()),
+        (NONCE, &nonce),
+        (WRAPPED_KEYS, &wrapped_keys),
+    ] {
+        inserted &= Metadata::insert_or_ignore(&mut *tx, name, value).await?;
+    }
+    if !inserted {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+    tx.commit().await?;
+    Ok(Some(provider))
+}
+
+pub(crate) fn derive(passphrase: &str, salt: &[u8], params: Params) -> Result<Vec<u8>, CacheVaultError> {
+    let mut kek = vec![0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+        passphrase.as_bytes(),
+        salt,
+        &mut kek,
+    )?;
+    Ok(kek)
+}
+
+fn wrap(kek: &[u8], keys: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CacheVaultError> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(kek));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload { msg: keys, aad: AAD };
+    let wrapped = cipher.encrypt(&nonce, payload).map_err(CacheVaultError::ChaCha20)?;
+    Ok((nonce.to_vec(), wrapped))
+}
+
+fn unwrap(kek: &[u8], nonce: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, CacheVaultError> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(kek));
+    if nonce.len() != 12 {
+        return Err(CacheVaultError::ChaCha20(chacha20poly1305::Error));
+    }
+    let payload = Payload { msg: wrapped, aad: AAD };
+    let keys = cipher
+        .decrypt(GenericArray::from_slice(nonce), payload)
+        .map_err(|_| CacheVaultError::InvalidPassphrase)?;
+    if keys.len() != 64 {
+        return Err(CacheVaultError::InvalidKeyLength(keys.len()));
+    }
+    Ok(keys)
+}
+
+async fn fetch(pool: &SqlitePool, name: &str) -> Result<Option<Vec<u8>>, CacheVaultError> {
+    Ok(Metadata::fetch_optional(pool, name).await?.map(|m| m.value))
+}
+
+async fn fetch_required(pool: &SqlitePool, name: &str) -> Result<Vec<u8>, CacheVaultError> {
+    fetch(pool, name)
+        .await?
+        .ok_or_else(|| CacheVaultError::Unknown(format!("metadata {:?} is missing", name)))
+}
+
+async fn fetch_u32(pool: &SqlitePool, name: &str) -> Result<u32, CacheVaultError> {
+    let value = fetch_required(pool, name).await?;
+    let bytes = value
+        .try_into()
+        .map_err(|_| CacheVaultError::Unknown(format!("metadata {:?} is malformed", name)))?;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -0,0 +1,305 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry::Vacant;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::audit::AuditOperation;
+use crate::envelope::NamespaceKeys;
+use crate::error::CacheVaultError;
+use crate::export::{write_private, ConflictPolicy};
+use crate::models::{Attribute, Entry};
+use crate::vault::{NewEntry, Vault};
+
+/// Document format for [`Vault::export_plaintext`] and [`Vault::import_plaintext`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PlaintextFormat {
+    /// An array of objects with `namespace`, `key_name`, `value`, `content_type`, `attributes`
+    /// and `expired_at`, covering every namespace.
+    Json,
+    /// The same document as [`PlaintextFormat::Json`], in YAML.
+    Yaml,
+    /// `KEY='value'` lines for the entries of one namespace, without attributes or expiry.
+    Dotenv { namespace: String },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PlaintextEntry {
+    namespace: String,
+    key_name: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default)]
+    attributes: BTreeMap<String, String>,
+    #[serde(default)]
+    expired_at: Option<NaiveDateTime>,
+}
+
+/// Keys that dotenv parsers accept unquoted.
+fn is_dotenv_key(key_name: &str) -> bool {
+    key_name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn to_dotenv(entries: &[PlaintextEntry]) -> Result<String, CacheVaultError> {
+    let mut out = String::new();
+    for entry in entries {
+        if !is_dotenv_key(&entry.key_name) {
+            return Err(CacheVaultError::InvalidDocument(format!(
+                "{:?} cannot be used as a .env key",
+                entry.key_name
+            )));
+        }
+        // Single quotes keep `$`, `#`, `\` and newlines literal; a quote closes them, adds an
+        // escaped quote and reopens them.
+        out.push_str(&format!(
+            "{}='{}'\n",
+            entry.key_name,
+            entry.value.replace('\'', "'\\''")
+        ));
+    }
+    Ok(out)
+}
+
+fn from_dotenv(namespace: &str, document: &[u8]) -> Result<Vec<PlaintextEntry>, CacheVaultError> {
+    dotenvy::from_read_iter(document)
+        .map(|item| {
+            let (key_name, value) = item.map_err(|e| CacheVaultError::InvalidDocument(e.to_string()))?;
+            Ok(PlaintextEntry {
+                namespace: namespace.to_string(),
+                key_name,
+                value,
+                content_type: None,
+                attributes: BTreeMap::new(),
+                expired_at: None,
+            })
+        })
+        .collect()
+}
+
+impl Vault {
+    /// Writes the unexpired entries, decrypted, to `path` in `format` and returns how many were
+    /// written.
+    ///
+    /// This is for moving secrets to other tools; use [`Vault::export`] for backups. Fails with
+    /// [`CacheVaultError::NonUtf8Value`] naming the first entry whose value is not valid UTF-8,
+    /// such as one saved as bytes or MessagePack.
+    pub async fn export_plaintext(
+        &self,
+        path: impl AsRef<Path>,
+        format: &PlaintextFormat,
+    ) -> Result<u64, CacheVaultError> {
+        let namespace = match format {
+            PlaintextFormat::Dotenv { namespace } => Some(namespace.as_str()),
+            PlaintextFormat::Json | PlaintextFormat::Yaml => None,
+        };
+        let mut keys: HashMap<String, NamespaceKeys> = HashMap::new();
+        let mut entries = Vec::new();
+        for entry in Entry::fetch_all(self.pool()).await? {
+            if entry.is_expired() || namespace.is_some_and(|namespace| namespace != entry.namespace) {
+                continue;
+            }
+            if let Vacant(vacant) = keys.entry(entry.namespace.clone()) {
+                vacant.insert(self.existing_namespace_keys(&entry.namespace).await?);
+            }
+            let keys = &keys[&entry.namespace];
+            let attributes = Attribute::fetch_all(self.pool(), entry.id)
+                .await?
+                .iter()
+                .map(|a| Ok((a.name.to_string(), a.plaintext(keys)?)))
+                .collect::<Result<BTreeMap<String, String>, CacheVaultError>>()?;
+            let value = String::from_utf8(entry.plaintext_bytes(keys)?).map_err(|_| CacheVaultError::NonUtf8Value {
+                namespace: entry.namespace.clone(),
+                key_name: entry.key_name.clone(),
+            })?;
+            entries.push(PlaintextEntry {
+                value,
+                namespace: entry.namespace,
+                key_name: entry.key_name,
+                content_type: entry.content_type,
+                attributes,
+                expired_at: entry.expired_at,
+            });
+        }
+
+        self.audit_all(
+            AuditOperation::Export,
+            entries.iter().map(|e| (e.namespace.as_str(), e.key_name.as_str())),
+        )
+        .await?;
+        let document = match format {
+            PlaintextFormat::Json => serde_json::to_string_pretty(&entries)? + "\n",
+            PlaintextFormat::Yaml => serde_yaml::to_string(&entries)?,
+            PlaintextFormat::Dotenv { .. } => to_dotenv(&entries)?,
+        };
+        write_private(path.as_ref(), document.as_bytes()).await?;
+        Ok(entries.len() as u64)
+    }
+
+    /// Saves the entries of a plaintext document, encrypting them with this vault's keys, and
+    /// returns how many were saved.
+    ///
+    /// Entries that already exist are handled according to `conflict_policy`. Everything is
+    /// imported in one transaction, so on error nothing is.
+    pub async fn import_plaintext(
+        &self,
+        path: impl AsRef<Path>,
+        format: &PlaintextFormat,
+        conflict_policy: ConflictPolicy,
+    ) -> Result<u64, CacheVaultError> {
+        let document = tokio::fs::read(path).await?;
+        let entries: Vec<PlaintextEntry> = match format {
+            PlaintextFormat::Json => serde_json::from_slice(&document)?,
+            PlaintextFormat::Yaml => serde_yaml::from_slice(&document)?,
+            PlaintextFormat::Dotenv { namespace } => from_dotenv(namespace, &document)?,
+        };
+        let entries = entries
+            .iter()
+            .map(|entry| NewEntry {
+                content_type: entry.content_type.as_deref(),
+                attributes: Some(entry.attributes.clone().into_iter().collect()),
+                expired_at: entry.expired_at,
+                ..NewEntry::new(&entry.namespace, &entry.key_name, entry.value.as_bytes())
+            })
+            .collect();
+        self.import_entries(entries, &HashMap::new(), conflict_policy).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::tests::open_test_vault;
+    use chrono::{Duration, Utc};
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn test_export_and_import_plaintext() -> Result<(), CacheVaultError> {
+        let (source, _source_dir) = open_test_vault().await?;
+        let expired_at = (Utc::now() + Duration::hours(1)).naive_utc();
+        let past = (Utc::now() - Duration::seconds(1)).naive_utc();
+        let attributes = HashMap::from([(String::from("env"), String::from("prod"))]);
+        source
+            .save(
+                "aws",
+                "prod/token",
+                "line1\nline2 \"quoted\"",
+                Some(attributes.clone()),
+                Some(expired_at),
+            )
+            .await?;
+        source.save_json("gcp", "config", &vec![1, 2], None, None).await?;
+        source.save("gcp", "stale", "value", None, Some(past)).await?;
+
+        for format in [PlaintextFormat::Json, PlaintextFormat::Yaml] {
+            let file = NamedTempFile::new()?;
+            assert_eq!(source.export_plaintext(file.path(), &format).await?, 2);
+            let (destination, _destination_dir) = open_test_vault().await?;
+            assert_eq!(
+                destination
+                    .import_plaintext(file.path(), &format, ConflictPolicy::Fail)
+                    .await?,
+                2
+            );
+            let (value, imported_expired_at, attrs) = destination.fetch_with_attributes("aws", "prod/token").await?;
+            assert_eq!(value, "line1\nline2 \"quoted\"");
+            assert_eq!(imported_expired_at, Some(expired_at));
+            assert_eq!(attrs, Some(attributes.clone()));
+            assert_eq!(destination.fetch_json::<Vec<i32>>("gcp", "config").await?.0, vec![1, 2]);
+            assert!(destination.fetch("gcp", "stale").await.is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_dotenv() -> Result<(), CacheVaultError> {
+        let (source, _source_dir) = open_test_vault().await?;
+        let value = "it's $HOME # not a comment\\n\nsecond line";
+        source.save("app", "DATABASE_URL", value, None, None).await?;
+        source.save("app", "EMPTY", "", None, None).await?;
+        source.save("other", "IGNORED", "value", None, None).await?;
+        let format = PlaintextFormat::Dotenv {
+            namespace: String::from("app"),
+        };
+        let file = NamedTempFile::new()?;
+        assert_eq!(source.export_plaintext(file.path(), &format).await?, 2);
+
+        let (destination, _destination_dir) = open_test_vault().await?;
+        destination.save("app", "EMPTY", "existing", None, None).await?;
+        assert_eq!(
+            destination
+                .import_plaintext(file.path(), &format, ConflictPolicy::Skip)
+                .await?,
+            1
+        );
+        assert_eq!(destination.fetch("app", "DATABASE_URL").await?.0, value);
+        assert_eq!(destination.fetch("app", "EMPTY").await?.0, "existing");
+        assert!(destination.fetch("app", "IGNORED").await.is_err());
+
+        source.save("app", "not/a/key", "value", None, None).await?;
+        assert!(matches!(
+            source.export_plaintext(file.path(), &format).await,
+            Err(CacheVaultError::InvalidDocument(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_yaml_from_other_tools() -> Result<(), CacheVaultError> {
+        let file = NamedTempFile::new()?;
+        std::fs::write(
+            file.path(),
+            r#"
+- namespace: tls
+  key_name: server.pem
+  value: |
+    -----BEGIN CERTIFICATE-----
+    MIIBszCCAVmgAwIBAgIU
+    -----END CERTIFICATE-----
+  attributes:
+    issuer: &issuer internal-ca
+    copy: *issuer
+"#,
+        )?;
+        let (vault, _dir) = open_test_vault().await?;
+        assert_eq!(
+            vault
+                .import_plaintext(file.path(), &PlaintextFormat::Yaml, ConflictPolicy::Fail)
+                .await?,
+            1
+        );
+        let (value, _, attributes) = vault.fetch_with_attributes("tls", "server.pem").await?;
+        assert_eq!(
+            value,
+            "-----BEGIN CERTIFICATE-----\nMIIBszCCAVmgAwIBAgIU\n-----END CERTIFICATE-----\n"
+        );
+        assert_eq!(attributes.unwrap()["copy"], "internal-ca");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export_plaintext_to_existing_file() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault.save("app", "TOKEN", "value", None, None).await?;
+        let file = NamedTempFile::new()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(file.path(), std::fs::Permissions::from_mode(0o644))?;
+            vault.export_plaintext(file.path(), &PlaintextFormat::Json).await?;
+            assert_eq!(std::fs::metadata(file.path())?.permissions().mode() & 0o777, 0o600);
+        }
+
+        vault
+            .save_bytes("app", "BINARY", &[0, 159, 146, 150], None, None)
+            .await?;
+        assert!(matches!(
+            vault.export_plaintext(file.path(), &PlaintextFormat::Json).await,
+            Err(CacheVaultError::NonUtf8Value { key_name, .. }) if key_name == "BINARY"
+        ));
+        Ok(())
+    }
+}
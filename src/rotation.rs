@@ -0,0 +1,264 @@
+use std::collections::hash_map::Entry::Vacant;
+use std::collections::HashMap;
+
+use crate::crypt::{encrypt, encrypt_bytes, key_id};
+use crate::error::CacheVaultError;
+use crate::key::KeyProvider;
+use crate::models::*;
+use crate::vault::Vault;
+
+const BATCH_SIZE: i64 = 100;
+
+impl Vault {
+    /// Re-wraps every namespace data key that is not yet under the key provider's current
+    /// encryption key.
+    ///
+    /// Configure the new key as the encryption key and the old one as a decryption key before
+    /// calling this. Rows written before envelope encryption or in an older ciphertext format are
+    /// re-encrypted with their namespace's data key on the way. Work is done in batches, each in
+    /// its own transaction, so an interrupted rotation can simply be run again. Returns the number
+    /// of keys and rows rewritten.
+    pub async fn rotate_key(&self) -> Result<u64, CacheVaultError> {
+        let mut total = 0;
+        loop {
+            let count = self.rotate_key_batch(BATCH_SIZE).await?;
+            if count == 0 {
+                return Ok(total);
+            }
+            total += count;
+        }
+    }
+
+    /// Rewrites at most `limit` namespace keys, `limit` entries, `limit` attributes and `limit`
+    /// previous values kept by [`Vault::set_history_limit`], returning how many were rewritten.
+    pub async fn rotate_key_batch(&self, limit: i64) -> Result<u64, CacheVaultError> {
+        let current_key_id = key_id(&self.keys().encryption_key()?)?;
+        let mut count = 0;
+
+        let namespace_keys = NamespaceKey::fetch_outdated(self.pool(), &current_key_id, limit).await?;
+        let mut tx = self.pool().begin().await?;
+        for namespace_key in namespace_keys.iter() {
+            if namespace_key.rewrap(&mut *tx, self.keys()).await? {
+                count += 1;
+            }
+        }
+        tx.commit().await?;
+
+        let entries = Entry::fetch_outdated(self.pool(), limit).await?;
+        let mut keys = HashMap::new();
+        for entry in entries.iter() {
+            if let Vacant(vacant) = keys.entry(entry.namespace.clone()) {
+                vacant.insert(self.namespace_keys(&entry.namespace).await?);
+            }
+        }
+        let mut tx = self.pool().begin().await?;
+        for entry in entries.iter() {
+            let keys = &keys[&entry.namespace];
+            let key = keys.encryption_key()?;
+            let aad = Entry::associated_data(&entry.namespace, &entry.key_name);
+            let (encrypted_value, nonce) = encrypt_bytes(self.algorithm(), &key, &aad, &entry.plaintext_bytes(keys)?)?;
+            if Entry::update_ciphertext(
+                &mut *tx,
+                entry.id,
+                &entry.nonce,
+                &nonce,
+                &encrypted_value,
+                &key_id(&key)?,
+                self.algorithm(),
+            )
+            .await?
+            {
+                count += 1;
+            }
+        }
+        tx.commit().await?;
+
+        let versions = EntryVersion::fetch_outdated(self.pool(), limit).await?;
+        let mut version_entries = HashMap::new();
+        for version in versions.iter() {
+            if let Vacant(vacant) = version_entries.entry(version.entry_id) {
+                let entry = Entry::fetch_by_id(self.pool(), version.entry_id).await?;
+                if let Vacant(vacant) = keys.entry(entry.namespace.clone()) {
+                    vacant.insert(self.namespace_keys(&entry.namespace).await?);
+                }
+                vacant.insert(entry);
+            }
+        }
+        let mut tx = self.pool().begin().await?;
+        for version in versions.iter() {
+            let entry = &version_entries[&version.entry_id];
+            let keys = &keys[&entry.namespace];
+            let key = keys.encryption_key()?;
+            let aad = Entry::associated_data(&entry.namespace, &entry.key_name);
+            let plaintext = version.plaintext_bytes(keys, &entry.namespace, &entry.key_name)?;
+            let (encrypted_value, nonce) = encrypt_bytes(self.algorithm(), &key, &aad, &plaintext)?;
+            if EntryVersion::update_ciphertext(
+                &mut *tx,
+                version.id,
+                &version.nonce,
+                &nonce,
+                &encrypted_value,
+                &key_id(&key)?,
+                self.algorithm(),
+            )
+            .await?
+            {
+                count += 1;
+            }
+        }
+        tx.commit().await?;
+
+        let attributes = Attribute::fetch_outdated(self.pool(), limit).await?;
+        let mut namespaces = HashMap::new();
+        for attribute in attributes.iter() {
+            if let Vacant(vacant) = namespaces.entry(attribute.entry_id) {
+                let entry = Entry::fetch_by_id(self.pool(), attribute.entry_id).await?;
+                if let Vacant(vacant) = keys.entry(entry.namespace.clone()) {
+                    vacant.insert(self.namespace_keys(&entry.namespace).await?);
+                }
+                vacant.insert(entry.namespace);
+            }
+        }
+        let mut tx = self.pool().begin().await?;
+        for attribute in attributes.iter() {
+            let keys = &keys[&namespaces[&attribute.entry_id]];
+            let key = keys.encryption_key()?;
+            let aad = Attribute::associated_data(attribute.entry_id, &attribute.name);
+            let (encrypted_value, nonce) = encrypt(self.algorithm(), &key, &aad, attribute.plaintext(keys)?)?;
+            if Attribute::update_ciphertext(
+                &mut *tx,
+                attribute.id,
+                &attribute.nonce,
+                &nonce,
+                &encrypted_value,
+                &key_id(&key)?,
+                self.algorithm(),
+            )
+            .await?
+            {
+                count += 1;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::Algorithm;
+    use crate::key::StaticKeyProvider;
+    use crate::vault::tests::{open_test_vault, test_database_path};
+
+    #[tokio::test]
+    async fn test_rotate_key() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+
+        let old_keys = StaticKeyProvider::generate();
+        let old_key = old_keys.encryption_key()?;
+        let pepper = old_keys.pepper()?;
+        let vault = Vault::builder().path(&path).key_provider(old_keys).open().await?;
+        let attributes = HashMap::from([(String::from("attr1"), String::from("attr1-value"))]);
+        vault
+            .save("test", "test-key1", "test-value1", Some(attributes.clone()), None)
+            .await?;
+        vault.save("test", "test-key2", "test-value2", None, None).await?;
+        vault.save("test", "test-key3", "test-value3", None, None).await?;
+        vault.save("other", "test-key", "other-value", None, None).await?;
+        vault.pool().close().await;
+
+        let new_keys = StaticKeyProvider::generate();
+        let new_key = new_keys.encryption_key()?;
+        let rotating = StaticKeyProvider::new(new_key.clone(), pepper.clone())?.with_previous(old_key);
+        let vault = Vault::builder().path(&path).key_provider(rotating).open().await?;
+        assert_eq!(vault.fetch("test", "test-key1").await?.0, "test-value1");
+
+        // Only the namespace keys are re-wrapped; an interrupted rotation leaves the rest for the next run.
+        assert_eq!(vault.rotate_key_batch(1).await?, 1);
+        assert_eq!(vault.rotate_key().await?, 1);
+        assert_eq!(vault.rotate_key().await?, 0);
+        vault.pool().close().await;
+
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(StaticKeyProvider::new(new_key, pepper)?)
+            .open()
+            .await?;
+        let (value, _, attrs) = vault.fetch_with_attributes("test", "test-key1").await?;
+        assert_eq!(value, "test-value1");
+        assert_eq!(attrs, Some(attributes));
+        assert_eq!(vault.fetch("test", "test-key3").await?.0, "test-value3");
+        assert_eq!(vault.fetch("other", "test-key").await?.0, "other-value");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_format() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let key = vault.keys().encryption_key()?;
+        for key_name in ["legacy-key", "legacy-history-key"] {
+            let (encrypted_value, nonce) = encrypt(Algorithm::default(), &key, b"", String::from("legacy-value"))?;
+            sqlx::query(
+                r#"
+                  insert into
+                    entries (namespace, key_name, nonce, encrypted_value, created_at, updated_at)
+                    values ("test", $1, $2, $3, datetime('now'), datetime('now'))
+                "#,
+            )
+            .bind(key_name)
+            .bind(nonce)
+            .bind(encrypted_value)
+            .execute(vault.pool())
+            .await?;
+        }
+        assert_eq!(vault.fetch("test", "legacy-key").await?.0, "legacy-value");
+        // Overwriting keeps the legacy ciphertext in the history.
+        vault.set_history_limit("test", 1).await?;
+        vault
+            .save("test", "legacy-history-key", "new-value", None, None)
+            .await?;
+
+        assert_eq!(vault.rotate_key().await?, 2);
+        let entry = Entry::fetch(vault.pool(), "test", "legacy-key").await?;
+        let namespace_key = NamespaceKey::fetch_optional(vault.pool(), "test").await?.unwrap();
+        assert_eq!(entry.format_version, FORMAT_VERSION);
+        assert_eq!(entry.key_id, Some(namespace_key.data_key_id.clone()));
+        assert_eq!(vault.fetch("test", "legacy-key").await?.0, "legacy-value");
+
+        let entry = Entry::fetch(vault.pool(), "test", "legacy-history-key").await?;
+        let version = EntryVersion::fetch(vault.pool(), entry.id, 1).await?;
+        assert_eq!(version.format_version, FORMAT_VERSION);
+        assert_eq!(version.key_id, Some(namespace_key.data_key_id));
+        assert_eq!(
+            vault.fetch_history("test", "legacy-history-key", 1).await?,
+            "legacy-value"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_id() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(StaticKeyProvider::generate())
+            .open()
+            .await?;
+        vault.save("test", "test-key", "test-value", None, None).await?;
+        vault.pool().close().await;
+
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(StaticKeyProvider::generate())
+            .open()
+            .await?;
+        match vault.fetch("test", "test-key").await {
+            Err(CacheVaultError::UnknownKeyId(_)) => (),
+            _ => panic!("unexpected"),
+        }
+        Ok(())
+    }
+}
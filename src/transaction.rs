@@ -0,0 +1,255 @@
+use chrono::NaiveDateTime;
+use futures::future::BoxFuture;
+use sqlx::sqlite::SqliteConnection;
+use sqlx::{Connection, Sqlite, Transaction};
+use std::collections::HashMap;
+
+use crate::audit::{outcome, AuditOperation};
+use crate::envelope::namespace_keys_in;
+use crate::error::CacheVaultError;
+use crate::history::history_limit_in;
+use crate::models::{Attribute, Entry};
+use crate::vault::{AttributesMode, NewEntry, Vault};
+
+/// Writes grouped by [`Vault::transaction`]; they are committed together or not at all.
+pub struct VaultTransaction {
+    vault: Vault,
+    tx: Transaction<'static, Sqlite>,
+}
+
+impl VaultTransaction {
+    async fn save_entry(&mut self, entry: NewEntry<'_>) -> Result<(), CacheVaultError> {
+        let (namespace, key_name) = (entry.namespace, entry.key_name);
+        // A save that fails partway is undone on its own, so a caller that handles the error does
+        // not commit half of the entry with the rest of the transaction.
+        let mut savepoint = self.tx.begin().await?;
+        let result = write_entry(&mut savepoint, &self.vault, entry).await;
+        if result.is_ok() {
+            savepoint.commit().await?;
+        } else {
+            savepoint.rollback().await?;
+        }
+        self.vault
+            .audit_in(
+                &mut self.tx,
+                AuditOperation::Save,
+                namespace,
+                key_name,
+                outcome(&result),
+            )
+            .await?;
+        result?;
+        Ok(())
+    }
+
+    /// See [`Vault::save`].
+    pub async fn save(
+        &mut self,
+        namespace: &str,
+        key_name: &str,
+        value: &str,
+        attributes: Option<HashMap<String, String>>,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        self.save_bytes(namespace, key_name, value.as_bytes(), attributes, expired_at)
+            .await
+    }
+
+    /// See [`Vault::save_bytes`].
+    pub async fn save_bytes(
+        &mut self,
+        namespace: &str,
+        key_name: &str,
+        value: &[u8],
+        attributes: Option<HashMap<String, String>>,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        self.save_entry(NewEntry {
+            attributes,
+            expired_at,
+            ..NewEntry::new(namespace, key_name, value)
+        })
+        .await
+    }
+
+    /// See [`Vault::save_with_attributes_mode`].
+    pub async fn save_with_attributes_mode(
+        &mut self,
+        namespace: &str,
+        key_name: &str,
+        value: &str,
+        attributes: HashMap<String, String>,
+        mode: AttributesMode,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        self.save_entry(NewEntry {
+            attributes: Some(attributes),
+            attributes_mode: mode,
+            expired_at,
+            ..NewEntry::new(namespace, key_name, value.as_bytes())
+        })
+        .await
+    }
+
+    /// See [`Vault::delete`].
+    pub async fn delete(&mut self, namespace: &str, key_name: &str) -> Result<bool, CacheVaultError> {
+        let result = Entry::delete(&mut *self.tx, namespace, key_name).await;
+        let outcome = match result {
+            Ok(false) => "not_found",
+            ref result => outcome(result),
+        };
+        self.vault
+            .audit_in(&mut self.tx, AuditOperation::Delete, namespace, key_name, outcome)
+            .await?;
+        result
+    }
+
+    /// See [`Vault::delete_attribute`].
+    pub async fn delete_attribute(
+        &mut self,
+        namespace: &str,
+        key_name: &str,
+        name: &str,
+    ) -> Result<bool, CacheVaultError> {
+        let deleted = Attribute::delete(&mut *self.tx, namespace, key_name, name).await?;
+        let outcome = if deleted { "ok" } else { "not_found" };
+        self.vault
+            .audit_in(
+                &mut self.tx,
+                AuditOperation::DeleteAttribute,
+                namespace,
+                key_name,
+                outcome,
+            )
+            .await?;
+        Ok(deleted)
+    }
+}
+
+async fn write_entry(conn: &mut SqliteConnection, vault: &Vault, entry: NewEntry<'_>) -> Result<i64, CacheVaultError> {
+    let keys = namespace_keys_in(&mut *conn, vault.keys(), entry.namespace).await?;
+    let history_limit = history_limit_in(&mut *conn, entry.namespace).await?;
+    entry.write(conn, &keys, vault.algorithm(), history_limit).await
+}
+
+impl Vault {
+    /// Runs `f` in a database transaction, committing if it returns `Ok` and rolling back
+    /// otherwise.
+    ///
+    /// The transaction holds SQLite's write lock from its first write until it ends, so keep `f`
+    /// short and do not use the vault itself inside it.
+    ///
+    /// ```no_run
+    /// # async fn example(vault: cache_vault::Vault) -> Result<(), cache_vault::CacheVaultError> {
+    /// vault
+    ///     .transaction(|tx| {
+    ///         Box::pin(async move {
+    ///             tx.save("aws", "access-key-id", "AKIA...", None, None).await?;
+    ///             tx.save("aws", "secret-access-key", "...", None, None).await
+    ///         })
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn transaction<F, T>(&self, f: F) -> Result<T, CacheVaultError>
+    where
+        F: for<'t> FnOnce(&'t mut VaultTransaction) -> BoxFuture<'t, Result<T, CacheVaultError>>,
+    {
+        let mut tx = VaultTransaction {
+            vault: self.clone(),
+            tx: self.pool().begin().await?,
+        };
+        let result = f(&mut tx).await;
+        match result {
+            Ok(value) => {
+                tx.tx.commit().await?;
+                Ok(value)
+            }
+            Err(e) => {
+                tx.tx.rollback().await?;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::tests::open_test_vault;
+
+    #[tokio::test]
+    async fn test_transaction() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let attributes = HashMap::from([(String::from("attr1"), String::from("attr1-value"))]);
+        let count = vault
+            .transaction(|tx| {
+                Box::pin(async move {
+                    tx.save("test", "test-key1", "test-value1", Some(attributes), None)
+                        .await?;
+                    tx.save_bytes("new-namespace", "test-key2", b"test-value2", None, None)
+                        .await?;
+                    Ok(2)
+                })
+            })
+            .await?;
+        assert_eq!(count, 2);
+        let (value, _, attrs) = vault.fetch_with_attributes("test", "test-key1").await?;
+        assert_eq!(value, "test-value1");
+        assert_eq!(attrs.unwrap()["attr1"], "attr1-value");
+        assert_eq!(vault.fetch_bytes("new-namespace", "test-key2").await?.0, b"test-value2");
+
+        let result = vault
+            .transaction(|tx| {
+                Box::pin(async move {
+                    tx.save("test", "test-key3", "test-value3", None, None).await?;
+                    assert!(tx.delete("test", "test-key1").await?);
+                    Err::<(), _>(CacheVaultError::Unknown(String::from("abort")))
+                })
+            })
+            .await;
+        assert!(matches!(result, Err(CacheVaultError::Unknown(_))));
+        assert!(vault.fetch("test", "test-key3").await.is_err());
+        assert_eq!(vault.fetch("test", "test-key1").await?.0, "test-value1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_failed_save() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        sqlx::query(
+            r#"
+              create trigger reject_attribute before insert on attributes
+              when new.name = 'rejected'
+              begin
+                select raise(abort, 'rejected');
+              end
+            "#,
+        )
+        .execute(vault.pool())
+        .await?;
+
+        // The entry row is written before the attribute fails, and must not be committed when the
+        // error is handled.
+        let attributes = HashMap::from([(String::from("rejected"), String::from("value"))]);
+        vault
+            .transaction(|tx| {
+                Box::pin(async move {
+                    tx.save("test", "test-key1", "test-value1", None, None).await?;
+                    assert!(tx
+                        .save("test", "test-key2", "test-value2", Some(attributes), None)
+                        .await
+                        .is_err());
+                    Ok(())
+                })
+            })
+            .await?;
+        assert_eq!(vault.fetch("test", "test-key1").await?.0, "test-value1");
+        match vault.fetch("test", "test-key2").await {
+            Err(CacheVaultError::SqlxError(sqlx::Error::RowNotFound)) => (),
+            _ => panic!("unexpected"),
+        }
+        Ok(())
+    }
+}
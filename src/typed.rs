@@ -0,0 +1,169 @@
+use chrono::NaiveDateTime;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::CacheVaultError;
+use crate::vault::{NewEntry, Vault};
+
+/// Serialization format of a typed value, recorded as the entry's content type.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    fn serialize<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CacheVaultError> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(value)?),
+            Format::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, value: &[u8]) -> Result<T, CacheVaultError> {
+        match self {
+            Format::Json => Ok(serde_json::from_slice(value)?),
+            Format::MessagePack => Ok(rmp_serde::from_slice(value)?),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.content_type())
+    }
+}
+
+impl Vault {
+    /// Serializes `value` with `format` and saves it along with the format's content type.
+    pub async fn save_serialized<T: Serialize + ?Sized>(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        value: &T,
+        format: Format,
+        attributes: Option<HashMap<String, String>>,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        let value = format.serialize(value)?;
+        self.save_entry(NewEntry {
+            content_type: Some(format.content_type()),
+            attributes,
+            expired_at,
+            ..NewEntry::new(namespace, key_name, &value)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Fetches a value saved with [`Vault::save_serialized`] in the same `format`.
+    ///
+    /// Fails with [`CacheVaultError::ContentTypeMismatch`] if the value was saved in another format
+    /// or as a plain string or bytes.
+    pub async fn fetch_serialized<T: DeserializeOwned>(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        format: Format,
+    ) -> Result<(T, Option<NaiveDateTime>), CacheVaultError> {
+        let entry = self.fetch_entry(namespace, key_name).await?;
+        if entry.content_type.as_deref() != Some(format.content_type()) {
+            return Err(CacheVaultError::ContentTypeMismatch {
+                namespace: entry.namespace,
+                key_name: entry.key_name,
+                expected: format.content_type().to_string(),
+                found: entry.content_type,
+            });
+        }
+        let keys = self.existing_namespace_keys(namespace).await?;
+        Ok((format.deserialize(&entry.plaintext_bytes(&keys)?)?, entry.expired_at))
+    }
+
+    pub async fn save_json<T: Serialize + ?Sized>(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        value: &T,
+        attributes: Option<HashMap<String, String>>,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        self.save_serialized(namespace, key_name, value, Format::Json, attributes, expired_at)
+            .await
+    }
+
+    pub async fn fetch_json<T: DeserializeOwned>(
+        &self,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<(T, Option<NaiveDateTime>), CacheVaultError> {
+        self.fetch_serialized(namespace, key_name, Format::Json).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::tests::open_test_vault;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct Credentials {
+        user: String,
+        scopes: Vec<String>,
+        expires_in: u64,
+    }
+
+    #[tokio::test]
+    async fn test_save_serialized() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let credentials = Credentials {
+            user: String::from("alice"),
+            scopes: vec![String::from("read"), String::from("write")],
+            expires_in: 3600,
+        };
+        vault.save_json("test", "json-key", &credentials, None, None).await?;
+        vault
+            .save_serialized("test", "msgpack-key", &credentials, Format::MessagePack, None, None)
+            .await?;
+        vault.save("test", "string-key", "test-value", None, None).await?;
+
+        assert_eq!(
+            vault.fetch_json::<Credentials>("test", "json-key").await?.0,
+            credentials
+        );
+        let (value, _) = vault
+            .fetch_serialized::<Credentials>("test", "msgpack-key", Format::MessagePack)
+            .await?;
+        assert_eq!(value, credentials);
+        assert_eq!(
+            vault.fetch("test", "json-key").await?.0,
+            r#"{"user":"alice","scopes":["read","write"],"expires_in":3600}"#
+        );
+
+        match vault.fetch_json::<Credentials>("test", "msgpack-key").await {
+            Err(CacheVaultError::ContentTypeMismatch { expected, found, .. }) => {
+                assert_eq!(expected, "application/json");
+                assert_eq!(found.as_deref(), Some("application/msgpack"));
+            }
+            _ => panic!("unexpected"),
+        }
+        match vault.fetch_json::<Credentials>("test", "string-key").await {
+            Err(CacheVaultError::ContentTypeMismatch { found: None, .. }) => (),
+            _ => panic!("unexpected"),
+        }
+
+        // Overwriting with a plain string clears the content type.
+        vault.save("test", "json-key", "test-value", None, None).await?;
+        assert!(vault.fetch_json::<Credentials>("test", "json-key").await.is_err());
+        Ok(())
+    }
+}
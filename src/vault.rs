@@ -0,0 +1,988 @@
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use sqlx::sqlite::{SqliteConnection, SqlitePool, SqlitePoolOptions};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::audit::{outcome, AuditOperation, AUDIT_LOG};
+use crate::cache::InFlight;
+use crate::connection::{connect, default_path, migrate};
+use crate::crypt::Algorithm;
+use crate::digest::digest;
+use crate::envelope::namespace_keys_in;
+use crate::error::CacheVaultError;
+use crate::expiry::expires_in;
+use crate::history::history_limit_in;
+use crate::key::{KeyProvider, KeyringKeyProvider, LockedKeyProvider};
+use crate::list::EntryInfo;
+use crate::models::*;
+use crate::passphrase;
+
+const ALGORITHM: &str = "algorithm";
+
+/// How a save treats the attributes already stored on the entry.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AttributesMode {
+    /// Upserts the given attributes and keeps the others.
+    #[default]
+    Merge,
+    /// Upserts the given attributes and deletes the others.
+    Replace,
+    /// Deletes the attributes with the given names; their values are ignored.
+    RemoveListed,
+}
+
+/// Handle to a single vault database.
+///
+/// Cloning a `Vault` is cheap; clones share the same connection pool and key provider.
+#[derive(Clone)]
+pub struct Vault {
+    pool: SqlitePool,
+    keys: Arc<dyn KeyProvider>,
+    algorithm: Algorithm,
+    in_flight: Arc<InFlight>,
+    audit_log: bool,
+    passphrase_protected: bool,
+}
+
+/// Builder for [`Vault`], created by [`Vault::builder`].
+#[derive(Default)]
+pub struct VaultBuilder {
+    path: Option<PathBuf>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    pool_options: Option<SqlitePoolOptions>,
+    algorithm: Option<Algorithm>,
+    audit_log: Option<bool>,
+    passphrase_protected: bool,
+}
+
+/// An entry to be written by [`Vault::save_entry`] or a [`VaultTransaction`].
+pub(crate) struct NewEntry<'a> {
+    pub(crate) namespace: &'a str,
+    pub(crate) key_name: &'a str,
+    pub(crate) value: &'a [u8],
+    /// Stored in the clear next to the ciphertext.
+    pub(crate) content_type: Option<&'a str>,
+    pub(crate) attributes: Option<HashMap<String, String>>,
+    pub(crate) attributes_mode: AttributesMode,
+    pub(crate) expired_at: Option<NaiveDateTime>,
+    /// Every successful fetch pushes `expired_at` this far into the future.
+    pub(crate) sliding_ttl_millis: Option<i64>,
+    /// Version the stored entry must be at, or 0 if it must not exist yet.
+    pub(crate) expected_version: Option<i64>,
+    /// Creation and update times to keep instead of the time of the write, e.g. from a backup.
+    pub(crate) timestamps: Option<(NaiveDateTime, NaiveDateTime)>,
+}
+
+impl<'a> NewEntry<'a> {
+    pub(crate) fn new(namespace: &'a str, key_name: &'a str, value: &'a [u8]) -> Self {
+        NewEntry {
+            namespace,
+            key_name,
+            value,
+            content_type: None,
+            attributes: None,
+            attributes_mode: AttributesMode::Merge,
+            expired_at: None,
+            sliding_ttl_millis: None,
+            expected_version: None,
+            timestamps: None,
+        }
+    }
+
+    /// Upserts the entry and applies its attributes through `conn`, which should be inside a
+    /// transaction, returning the entry's new version. `keys` must be the namespace's keys and
+    /// `history_limit` its [`Vault::history_limit`].
+    pub(crate) async fn write(
+        self,
+        conn: &mut SqliteConnection,
+        keys: &dyn KeyProvider,
+        algorithm: Algorithm,
+        history_limit: u32,
+    ) -> Result<i64, CacheVaultError> {
+        if let Some(expected) = self.expected_version.filter(|expected| *expected > 0) {
+            // Only an update can match; the upsert would insert the missing entry.
+            if Entry::fetch_version(&mut *conn, self.namespace, self.key_name)
+                .await?
+                .is_none()
+            {
+                return Err(self.version_mismatch(expected, None));
+            }
+        }
+        if history_limit > 0 {
+            EntryVersion::archive(&mut *conn, self.namespace, self.key_name, self.expected_version).await?;
+        }
+        let entry_id = Entry::upsert(
+            &mut *conn,
+            keys,
+            algorithm,
+            self.namespace,
+            self.key_name,
+            self.value,
+            self.content_type,
+            self.expired_at,
+            self.sliding_ttl_millis,
+            self.expected_version,
+        )
+        .await;
+        let (entry_id, version) = match (entry_id, self.expected_version) {
+            (Err(CacheVaultError::SqlxError(sqlx::Error::RowNotFound)), Some(expected)) => {
+                let actual = Entry::fetch_version(&mut *conn, self.namespace, self.key_name).await?;
+                return Err(self.version_mismatch(expected, actual));
+            }
+            (result, _) => result?,
+        };
+        if history_limit > 0 {
+            EntryVersion::prune(&mut *conn, self.namespace, Some(self.key_name), history_limit.into()).await?;
+        }
+        if let Some((created_at, updated_at)) = self.timestamps {
+            Entry::set_timestamps(&mut *conn, entry_id, created_at, updated_at).await?;
+        }
+        let attributes = self.attributes.unwrap_or_default();
+        if self.attributes_mode == AttributesMode::RemoveListed {
+            for name in attributes.keys() {
+                Attribute::delete_by_name(&mut *conn, entry_id, name).await?;
+            }
+        } else {
+            if self.attributes_mode == AttributesMode::Replace {
+                let names = attributes.keys().map(String::as_str).collect::<Vec<_>>();
+                Attribute::delete_except(&mut *conn, entry_id, &names).await?;
+            }
+            for (name, value) in attributes.iter() {
+                let _ = Attribute::upsert(&mut *conn, keys, algorithm, entry_id, name, value).await?;
+            }
+        }
+        Ok(version)
+    }
+
+    fn version_mismatch(&self, expected: i64, actual: Option<i64>) -> CacheVaultError {
+        CacheVaultError::VersionMismatch {
+            namespace: self.namespace.to_string(),
+            key_name: self.key_name.to_string(),
+            expected,
+            actual,
+        }
+    }
+}
+
+impl VaultBuilder {
+    /// Database file to open. Defaults to `CACHE_VAULT_DATABASE_PATH` or
+    /// `<config dir>/cache-vault/cache-vault.db`.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Where the encryption key and pepper come from. Defaults to the OS keyring.
+    ///
+    /// A database whose master key is [passphrase protected](VaultBuilder::passphrase_protected)
+    /// cannot be opened with another key provider.
+    pub fn key_provider(mut self, key_provider: impl KeyProvider + 'static) -> Self {
+        self.key_provider = Some(Arc::new(key_provider));
+        self.passphrase_protected = false;
+        self
+    }
+
+    /// Keeps the master key in the database, wrapped with a passphrase-derived key.
+    ///
+    /// The opened vault is locked until [`Vault::unlock`] is called.
+    pub fn passphrase_protected(mut self) -> Self {
+        self.key_provider = Some(Arc::new(LockedKeyProvider));
+        self.passphrase_protected = true;
+        self
+    }
+
+    pub fn pool_options(mut self, pool_options: SqlitePoolOptions) -> Self {
+        self.pool_options = Some(pool_options);
+        self
+    }
+
+    /// AEAD for new writes, remembered in the database. Defaults to the one chosen when the vault
+    /// was created, or ChaCha20-Poly1305. Rows written with other algorithms stay readable.
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = Some(algorithm);
+        self
+    }
+
+    /// Records every save, read, export, delete and restore in the audit log, remembered in the database.
+    /// Defaults to the setting chosen last, or off. See [`Vault::verify_audit_log`].
+    pub fn audit_log(mut self, enabled: bool) -> Self {
+        self.audit_log = Some(enabled);
+        self
+    }
+
+    /// Connects to the database and applies pending migrations.
+    pub async fn open(self) -> Result<Vault, CacheVaultError> {
+        let path = match self.path {
+            Some(path) => path,
+            None => default_path()?,
+        };
+        let pool = connect(&path, self.pool_options.unwrap_or_default()).await?;
+        migrate(&pool).await?;
+        let algorithm = match self.algorithm {
+            Some(algorithm) => {
+                Metadata::upsert(&pool, ALGORITHM, algorithm.as_str().as_bytes()).await?;
+                algorithm
+            }
+            None => match Metadata::fetch_optional(&pool, ALGORITHM).await? {
+                Some(metadata) => String::from_utf8(metadata.value)?.parse()?,
+                None => Algorithm::default(),
+            },
+        };
+        let audit_log = match self.audit_log {
+            Some(enabled) => {
+                Metadata::upsert(&pool, AUDIT_LOG, &[enabled.into()]).await?;
+                enabled
+            }
+            None => match Metadata::fetch_optional(&pool, AUDIT_LOG).await? {
+                Some(metadata) => metadata.value == [1],
+                None => false,
+            },
+        };
+        // Rows encrypted under the wrapped master key cannot be read with any other key.
+        if !self.passphrase_protected && passphrase::is_initialized(&pool).await? {
+            return Err(CacheVaultError::PassphraseProtected);
+        }
+        let keys = self
+            .key_provider
+            .unwrap_or_else(|| Arc::new(KeyringKeyProvider::default()));
+        Ok(Vault {
+            pool,
+            keys,
+            algorithm,
+            in_flight: Arc::default(),
+            audit_log,
+            passphrase_protected: self.passphrase_protected,
+        })
+    }
+}
+
+impl Vault {
+    pub fn builder() -> VaultBuilder {
+        VaultBuilder::default()
+    }
+
+    /// Unlocks a [passphrase protected](VaultBuilder::passphrase_protected) vault.
+    ///
+    /// The first unlock of a new database generates the master key and wraps it with `passphrase`.
+    /// Clones made before unlocking stay locked. Fails with
+    /// [`CacheVaultError::NotPassphraseProtected`] on a vault opened with another key provider.
+    pub async fn unlock(&mut self, passphrase: &str) -> Result<(), CacheVaultError> {
+        if !self.passphrase_protected {
+            return Err(CacheVaultError::NotPassphraseProtected);
+        }
+        let keys = passphrase::unlock(self.pool(), passphrase).await?;
+        self.keys = Arc::new(keys);
+        Ok(())
+    }
+
+    pub(crate) fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    pub(crate) fn keys(&self) -> &dyn KeyProvider {
+        &*self.keys
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub(crate) fn in_flight(&self) -> &InFlight {
+        &self.in_flight
+    }
+
+    pub(crate) fn audit_log_enabled(&self) -> bool {
+        self.audit_log
+    }
+
+    pub async fn save(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        value: &str,
+        attributes: Option<HashMap<String, String>>,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        self.save_bytes(namespace, key_name, value.as_bytes(), attributes, expired_at)
+            .await
+    }
+
+    /// Like [`Vault::save`] but stores arbitrary bytes, e.g. DER certificates or compressed payloads.
+    pub async fn save_bytes(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        value: &[u8],
+        attributes: Option<HashMap<String, String>>,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        self.save_entry(NewEntry {
+            attributes,
+            expired_at,
+            ..NewEntry::new(namespace, key_name, value)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Like [`Vault::save`] but `mode` decides what happens to attributes already on the entry.
+    pub async fn save_with_attributes_mode(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        value: &str,
+        attributes: HashMap<String, String>,
+        mode: AttributesMode,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<(), CacheVaultError> {
+        self.save_entry(NewEntry {
+            attributes: Some(attributes),
+            attributes_mode: mode,
+            expired_at,
+            ..NewEntry::new(namespace, key_name, value.as_bytes())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Like [`Vault::save`] but only if the stored entry is still at `expected_version`, as returned
+    /// by [`Vault::fetch_with_version`]; pass 0 to only create a new entry.
+    ///
+    /// Fails with [`CacheVaultError::VersionMismatch`] if another writer got there first. Returns
+    /// the new version.
+    pub async fn save_if_version(
+        &self,
+        namespace: &str,
+        key_name: &str,
+        expected_version: i64,
+        value: &str,
+        attributes: Option<HashMap<String, String>>,
+        expired_at: Option<NaiveDateTime>,
+    ) -> Result<i64, CacheVaultError> {
+        self.save_entry(NewEntry {
+            attributes,
+            expired_at,
+            expected_version: Some(expected_version),
+            ..NewEntry::new(namespace, key_name, value.as_bytes())
+        })
+        .await
+    }
+
+    /// Writes `entry` and its attributes in one transaction, returning the entry's new version.
+    pub(crate) async fn save_entry(&self, entry: NewEntry<'_>) -> Result<i64, CacheVaultError> {
+        let (namespace, key_name) = (entry.namespace, entry.key_name);
+        let mut tx = self.pool().begin().await?;
+        let keys = namespace_keys_in(&mut tx, self.keys(), namespace).await?;
+        let history_limit = history_limit_in(&mut *tx, namespace).await?;
+        let result = entry.write(&mut tx, &keys, self.algorithm(), history_limit).await;
+        if result.is_ok() {
+            self.audit_in(&mut tx, AuditOperation::Save, namespace, key_name, "ok")
+                .await?;
+            tx.commit().await?;
+        } else {
+            tx.rollback().await?;
+            self.audit(AuditOperation::Save, namespace, key_name, outcome(&result))
+                .await?;
+        }
+        result
+    }
+
+    /// Reads the entry row, treating it as missing once `expired_at` has passed and extending it
+    /// if it has a sliding expiration.
+    pub(crate) async fn fetch_entry(&self, namespace: &str, key_name: &str) -> Result<Entry, CacheVaultError> {
+        let result = self.fetch_unexpired(namespace, key_name).await;
+        self.audit(AuditOperation::Fetch, namespace, key_name, outcome(&result))
+            .await?;
+        result
+    }
+
+    async fn fetch_unexpired(&self, namespace: &str, key_name: &str) -> Result<Entry, CacheVaultError> {
+        let mut entry = Entry::fetch(self.pool(), namespace, key_name).await?;
+        if entry.is_expired() {
+            return Err(CacheVaultError::Expired {
+                namespace: entry.namespace,
+                key_name: entry.key_name,
+            });
+        }
+        self.slide_expiration(&mut entry).await?;
+        Ok(entry)
+    }
+
+    /// Pushes `expired_at` forward if the entry was saved with a sliding expiration, unless it was
+    /// saved again since `entry` was read.
+    pub(crate) async fn slide_expiration(&self, entry: &mut Entry) -> Result<(), CacheVaultError> {
+        if let Some(millis) = entry.sliding_ttl_millis {
+            let ttl = TimeDelta::try_milliseconds(millis)
+                .ok_or_else(|| CacheVaultError::InvalidDuration(format!("sliding ttl {}ms is out of range", millis)))?;
+            let expired_at = expires_in(ttl)?;
+            if Entry::extend_expiration(self.pool(), entry.id, entry.version, expired_at).await? {
+                entry.expired_at = Some(expired_at);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails with [`CacheVaultError::Expired`] once the entry's `expired_at` has passed; see
+    /// [`Vault::fetch_including_expired`] for reading stale values.
+    pub async fn fetch(
+        &self,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<(String, Option<NaiveDateTime>), CacheVaultError> {
+        let entry = self.fetch_entry(namespace, key_name).await?;
+        let keys = self.existing_namespace_keys(namespace).await?;
+        Ok((entry.plaintext(&keys)?, entry.expired_at))
+    }
+
+    /// Like [`Vault::fetch`] but also returns the entry's version, which goes up by one on every
+    /// save, for use with [`Vault::save_if_version`].
+    pub async fn fetch_with_version(
+        &self,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<(String, Option<NaiveDateTime>, i64), CacheVaultError> {
+        let entry = self.fetch_entry(namespace, key_name).await?;
+        let keys = self.existing_namespace_keys(namespace).await?;
+        Ok((entry.plaintext(&keys)?, entry.expired_at, entry.version))
+    }
+
+    /// Like [`Vault::fetch`] but also returns entries whose `expired_at` has passed.
+    pub async fn fetch_including_expired(
+        &self,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<(String, Option<NaiveDateTime>), CacheVaultError> {
+        let result = Entry::fetch(self.pool(), namespace, key_name).await;
+        self.audit(AuditOperation::Fetch, namespace, key_name, outcome(&result))
+            .await?;
+        let entry = result?;
+        let keys = self.existing_namespace_keys(namespace).await?;
+        Ok((entry.plaintext(&keys)?, entry.expired_at))
+    }
+
+    /// Like [`Vault::fetch`] but returns the raw bytes, for values saved with [`Vault::save_bytes`].
+    pub async fn fetch_bytes(
+        &self,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<(Vec<u8>, Option<NaiveDateTime>), CacheVaultError> {
+        let entry = self.fetch_entry(namespace, key_name).await?;
+        let keys = self.existing_namespace_keys(namespace).await?;
+        Ok((entry.plaintext_bytes(&keys)?, entry.expired_at))
+    }
+
+    pub async fn fetch_with_attributes(
+        &self,
+        namespace: &str,
+        key_name: &str,
+    ) -> Result<(String, Option<NaiveDateTime>, Option<HashMap<String, String>>), CacheVaultError> {
+        let entry = self.fetch_entry(namespace, key_name).await?;
+        let keys = self.existing_namespace_keys(namespace).await?;
+        let attributes = Attribute::fetch_all(self.pool(), entry.id)
+            .await?
+            .iter()
+            .map(|a| Ok((a.name.to_string(), a.plaintext(&keys)?)))
+            .collect::<Result<HashMap<String, String>, CacheVaultError>>()?;
+        if attributes.is_empty() {
+            Ok((entry.plaintext(&keys)?, entry.expired_at, None))
+        } else {
+            Ok((entry.plaintext(&keys)?, entry.expired_at, Some(attributes)))
+        }
+    }
+
+    /// Deletes the entry and its attributes, returning whether it existed.
+    pub async fn delete(&self, namespace: &str, key_name: &str) -> Result<bool, CacheVaultError> {
+        let mut tx = self.pool().begin().await?;
+        let result = Entry::delete(&mut *tx, namespace, key_name).await;
+        match result {
+            Ok(deleted) => {
+                let outcome = if deleted { "ok" } else { "not_found" };
+                self.audit_in(&mut tx, AuditOperation::Delete, namespace, key_name, outcome)
+                    .await?;
+                tx.commit().await?;
+            }
+            Err(_) => {
+                tx.rollback().await?;
+                self.audit(AuditOperation::Delete, namespace, key_name, outcome(&result))
+                    .await?;
+            }
+        }
+        result
+    }
+
+    /// Deletes one attribute of the entry, returning whether it existed.
+    pub async fn delete_attribute(&self, namespace: &str, key_name: &str, name: &str) -> Result<bool, CacheVaultError> {
+        let mut tx = self.pool().begin().await?;
+        let deleted = Attribute::delete(&mut *tx, namespace, key_name, name).await?;
+        let outcome = if deleted { "ok" } else { "not_found" };
+        self.audit_in(&mut tx, AuditOperation::DeleteAttribute, namespace, key_name, outcome)
+            .await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// Deletes every entry in `namespace` along with the namespace's data key, returning whether
+    /// anything was deleted.
+    ///
+    /// Without the data key, copies of the rows left in backups or free pages can no longer be
+    /// decrypted.
+    pub async fn delete_namespace(&self, namespace: &str) -> Result<bool, CacheVaultError> {
+        let mut tx = self.pool().begin().await?;
+        let count = Entry::delete_namespace(&mut *tx, namespace).await?;
+        let key_deleted = NamespaceKey::delete(&mut *tx, namespace).await?;
+        let deleted = count > 0 || key_deleted;
+        let outcome = if deleted { "ok" } else { "not_found" };
+        self.audit_in(&mut tx, AuditOperation::DeleteNamespace, namespace, "", outcome)
+            .await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// Finds the unexpired entries in `namespace` whose attributes include every name/value pair
+    /// in `attributes`.
+    ///
+    /// Values are matched exactly through their peppered digests, so nothing is decrypted.
+    pub async fn search_by_attributes(
+        &self,
+        namespace: &str,
+        attributes: &HashMap<String, String>,
+    ) -> Result<Vec<EntryInfo>, CacheVaultError> {
+        let pepper = self.keys().pepper()?;
+        let hashed = attributes
+            .iter()
+            .map(|(name, value)| Ok((name.as_str(), digest(&pepper, value.as_bytes())?)))
+            .collect::<Result<Vec<_>, CacheVaultError>>()?;
+        Entry::search_by_attributes(self.pool(), namespace, &hashed, Utc::now().naive_utc()).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::key::StaticKeyProvider;
+    use chrono::Duration;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// Returns a database path in a new temporary directory. The directory and the database in it
+    /// are removed when the returned `TempDir` is dropped, so keep it alive for the whole test.
+    pub(crate) fn test_database_path() -> Result<(PathBuf, TempDir), CacheVaultError> {
+        let dir = tempfile::tempdir()?;
+        Ok((dir.path().join("vault.db"), dir))
+    }
+
+    /// Opens a vault with freshly generated keys in a database from [`test_database_path`].
+    pub(crate) async fn open_test_vault() -> Result<(Vault, TempDir), CacheVaultError> {
+        let (path, dir) = test_database_path()?;
+        let vault = Vault::builder()
+            .path(path)
+            .key_provider(StaticKeyProvider::generate())
+            .open()
+            .await?;
+        Ok((vault, dir))
+    }
+
+    #[tokio::test]
+    async fn test_save() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault.save("test", "test-key1", "test-value1", None, None).await?;
+        vault.save("test", "test-key2", "test-value2", None, None).await?;
+        let (value1, _) = vault.fetch("test", "test-key1").await?;
+        assert_eq!(value1, "test-value1");
+        let (value2, _) = vault.fetch("test", "test-key2").await?;
+        assert_eq!(value2, "test-value2");
+
+        let (value1, _, attributes) = vault.fetch_with_attributes("test", "test-key1").await?;
+        assert_eq!(value1, "test-value1");
+        assert_eq!(attributes, None);
+
+        match vault.fetch("test", "no-such-key").await {
+            Err(e) => match e {
+                CacheVaultError::SqlxError(sqlx::Error::RowNotFound) => (),
+                _ => panic!("unexpected"),
+            },
+            Ok(_) => panic!("unexpected"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_with_attributes() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let attributes = HashMap::from([
+            (String::from("attr1"), String::from("attr1-value")),
+            (String::from("attr2"), String::from("attr2-value")),
+            (String::from("attr3"), String::from("attr3-value")),
+        ]);
+        vault
+            .save("test", "test-key1", "test-value1", Some(attributes.clone()), None)
+            .await?;
+
+        if let (value1, _, Some(attrs)) = vault.fetch_with_attributes("test", "test-key1").await? {
+            assert_eq!(value1, "test-value1");
+            assert_eq!(attrs, attributes);
+        } else {
+            panic!("unexpected");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let past = Utc::now().naive_utc() - Duration::seconds(1);
+        let future = Utc::now().naive_utc() + Duration::hours(1);
+        vault
+            .save("test", "expired-key", "stale-value", None, Some(past))
+            .await?;
+        vault
+            .save("test", "fresh-key", "fresh-value", None, Some(future))
+            .await?;
+
+        match vault.fetch("test", "expired-key").await {
+            Err(CacheVaultError::Expired { namespace, key_name }) => {
+                assert_eq!(namespace, "test");
+                assert_eq!(key_name, "expired-key");
+            }
+            _ => panic!("unexpected"),
+        }
+        assert!(vault.fetch_bytes("test", "expired-key").await.is_err());
+        assert!(vault.fetch_with_attributes("test", "expired-key").await.is_err());
+        assert_eq!(
+            vault.fetch_including_expired("test", "expired-key").await?,
+            (String::from("stale-value"), Some(past))
+        );
+        assert_eq!(vault.fetch("test", "fresh-key").await?.0, "fresh-value");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let attributes = HashMap::from([
+            (String::from("attr1"), String::from("attr1-value")),
+            (String::from("attr2"), String::from("attr2-value")),
+        ]);
+        vault
+            .save("test", "test-key1", "test-value1", Some(attributes.clone()), None)
+            .await?;
+        vault
+            .save("test", "test-key2", "test-value2", Some(attributes), None)
+            .await?;
+        vault.save("other", "test-key", "other-value", None, None).await?;
+
+        assert!(vault.delete_attribute("test", "test-key1", "attr1").await?);
+        assert!(!vault.delete_attribute("test", "test-key1", "attr1").await?);
+        assert!(!vault.delete_attribute("test", "no-such-key", "attr2").await?);
+        let (_, _, attrs) = vault.fetch_with_attributes("test", "test-key1").await?;
+        assert_eq!(attrs.unwrap().keys().collect::<Vec<_>>(), vec!["attr2"]);
+
+        assert!(vault.delete("test", "test-key1").await?);
+        assert!(!vault.delete("test", "test-key1").await?);
+        assert!(vault.fetch("test", "test-key1").await.is_err());
+        let (count,): (i64,) = sqlx::query_as("select count(*) from attributes")
+            .fetch_one(vault.pool())
+            .await?;
+        assert_eq!(count, 2);
+
+        assert!(vault.delete_namespace("test").await?);
+        assert!(!vault.delete_namespace("test").await?);
+        assert!(NamespaceKey::fetch_optional(vault.pool(), "test").await?.is_none());
+        let (count,): (i64,) = sqlx::query_as("select count(*) from attributes")
+            .fetch_one(vault.pool())
+            .await?;
+        assert_eq!(count, 0);
+        assert_eq!(vault.fetch("other", "test-key").await?.0, "other-value");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_by_attributes() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let attributes = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        vault
+            .save(
+                "test",
+                "key1",
+                "value1",
+                Some(attributes(&[("env", "prod"), ("team", "a")])),
+                None,
+            )
+            .await?;
+        vault
+            .save(
+                "test",
+                "key2",
+                "value2",
+                Some(attributes(&[("env", "prod"), ("team", "b")])),
+                None,
+            )
+            .await?;
+        vault
+            .save(
+                "test",
+                "key3",
+                "value3",
+                Some(attributes(&[("env", "dev"), ("team", "a")])),
+                None,
+            )
+            .await?;
+        vault
+            .save("other", "key1", "value1", Some(attributes(&[("env", "prod")])), None)
+            .await?;
+
+        let search = |pairs: &'static [(&'static str, &'static str)]| {
+            let vault = vault.clone();
+            async move {
+                let entries = vault.search_by_attributes("test", &attributes(pairs)).await?;
+                Ok::<_, CacheVaultError>(entries.into_iter().map(|e| e.key_name).collect::<Vec<_>>())
+            }
+        };
+        assert_eq!(search(&[("env", "prod")]).await?, vec!["key1", "key2"]);
+        assert_eq!(search(&[("env", "prod"), ("team", "a")]).await?, vec!["key1"]);
+        assert_eq!(search(&[("team", "prod")]).await?, Vec::<String>::new());
+        assert_eq!(search(&[("env", "staging")]).await?, Vec::<String>::new());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attributes_mode() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let attributes = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let save = |pairs, mode| {
+            vault.save_with_attributes_mode("test", "test-key", "test-value", attributes(pairs), mode, None)
+        };
+        let fetch = || async {
+            let (_, _, attrs) = vault.fetch_with_attributes("test", "test-key").await?;
+            Ok::<_, CacheVaultError>(attrs.unwrap_or_default())
+        };
+
+        save(&[("a", "1"), ("b", "2")], AttributesMode::Merge).await?;
+        save(&[("b", "3"), ("c", "4")], AttributesMode::Merge).await?;
+        assert_eq!(fetch().await?, attributes(&[("a", "1"), ("b", "3"), ("c", "4")]));
+
+        save(&[("c", "5"), ("d", "6")], AttributesMode::Replace).await?;
+        assert_eq!(fetch().await?, attributes(&[("c", "5"), ("d", "6")]));
+
+        save(&[("c", ""), ("x", "")], AttributesMode::RemoveListed).await?;
+        assert_eq!(fetch().await?, attributes(&[("d", "6")]));
+
+        save(&[], AttributesMode::Replace).await?;
+        assert_eq!(fetch().await?, HashMap::new());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_if_version() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        assert_eq!(
+            vault.save_if_version("test", "token", 0, "token1", None, None).await?,
+            1
+        );
+        match vault.save_if_version("test", "token", 0, "token2", None, None).await {
+            Err(CacheVaultError::VersionMismatch { expected, actual, .. }) => {
+                assert_eq!((expected, actual), (0, Some(1)));
+            }
+            _ => panic!("unexpected"),
+        }
+
+        let (_, _, version) = vault.fetch_with_version("test", "token").await?;
+        assert_eq!(version, 1);
+        vault.save("test", "token", "token2", None, None).await?;
+        match vault
+            .save_if_version("test", "token", version, "token3", None, None)
+            .await
+        {
+            Err(CacheVaultError::VersionMismatch { expected, actual, .. }) => {
+                assert_eq!((expected, actual), (1, Some(2)));
+            }
+            _ => panic!("unexpected"),
+        }
+        assert_eq!(
+            vault.save_if_version("test", "token", 2, "token3", None, None).await?,
+            3
+        );
+        assert_eq!(
+            vault.fetch_with_version("test", "token").await?,
+            (String::from("token3"), None, 3)
+        );
+
+        // A missing entry is reported without writing anything.
+        sqlx::query(
+            r#"
+              create trigger reject_insert before insert on entries
+              when new.key_name = 'no-such-key'
+              begin
+                select raise(abort, 'inserted');
+              end
+            "#,
+        )
+        .execute(vault.pool())
+        .await?;
+        match vault
+            .save_if_version("test", "no-such-key", 1, "value", None, None)
+            .await
+        {
+            Err(CacheVaultError::VersionMismatch { actual: None, .. }) => (),
+            _ => panic!("unexpected"),
+        }
+        assert!(vault.fetch("test", "no-such-key").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_bytes() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        let value = vec![0x30, 0x82, 0xff, 0x00, 0xfe];
+        vault.save_bytes("test", "test-key", &value, None, None).await?;
+        assert_eq!(vault.fetch_bytes("test", "test-key").await?.0, value);
+        match vault.fetch("test", "test-key").await {
+            Err(CacheVaultError::NonUtf8Value { namespace, key_name }) => {
+                assert_eq!(namespace, "test");
+                assert_eq!(key_name, "test-key");
+            }
+            _ => panic!("unexpected"),
+        }
+
+        vault.save("test", "test-key2", "test-value2", None, None).await?;
+        assert_eq!(vault.fetch_bytes("test", "test-key2").await?.0, b"test-value2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_swapped_ciphertext() -> Result<(), CacheVaultError> {
+        let (vault, _dir) = open_test_vault().await?;
+        vault.save("prod", "token", "prod-token", None, None).await?;
+        vault.save("dev", "token", "dev-token", None, None).await?;
+        sqlx::query(
+            r#"
+              update entries set
+                (nonce, encrypted_value) = (select nonce, encrypted_value from entries where namespace = "prod")
+              where
+                namespace = "dev"
+            "#,
+        )
+        .execute(vault.pool())
+        .await?;
+        match vault.fetch("dev", "token").await {
+            Err(CacheVaultError::ChaCha20(_)) => (),
+            _ => panic!("unexpected"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlock() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+
+        let mut vault = Vault::builder().path(&path).passphrase_protected().open().await?;
+        match vault.save("test", "test-key", "test-value", None, None).await {
+            Err(CacheVaultError::Locked) => (),
+            _ => panic!("unexpected"),
+        }
+        vault.unlock("correct horse battery staple").await?;
+        vault.save("test", "test-key", "test-value", None, None).await?;
+        vault.pool().close().await;
+
+        let mut vault = Vault::builder().path(&path).passphrase_protected().open().await?;
+        match vault.unlock("wrong passphrase").await {
+            Err(CacheVaultError::InvalidPassphrase) => (),
+            _ => panic!("unexpected"),
+        }
+        vault.unlock("correct horse battery staple").await?;
+        assert_eq!(vault.fetch("test", "test-key").await?.0, "test-value");
+        vault.pool().close().await;
+
+        // Another key provider must not open the database, or reads and writes would use a key
+        // other than the wrapped master key.
+        match Vault::builder()
+            .path(&path)
+            .key_provider(StaticKeyProvider::generate())
+            .open()
+            .await
+        {
+            Err(CacheVaultError::PassphraseProtected) => (),
+            _ => panic!("unexpected"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlock_without_passphrase() -> Result<(), CacheVaultError> {
+        let (mut vault, _dir) = open_test_vault().await?;
+        vault.save("test", "test-key", "test-value", None, None).await?;
+        match vault.unlock("passphrase").await {
+            Err(CacheVaultError::NotPassphraseProtected) => (),
+            _ => panic!("unexpected"),
+        }
+        assert_eq!(vault.fetch("test", "test-key").await?.0, "test-value");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_first_unlock() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+
+        let mut vault1 = Vault::builder().path(&path).passphrase_protected().open().await?;
+        let mut vault2 = Vault::builder().path(&path).passphrase_protected().open().await?;
+        let (result1, result2) = tokio::join!(vault1.unlock("passphrase"), vault2.unlock("passphrase"));
+        result1?;
+        result2?;
+        vault1.save("test", "test-key", "test-value", None, None).await?;
+        assert_eq!(vault2.fetch("test", "test-key").await?.0, "test-value");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_algorithm() -> Result<(), CacheVaultError> {
+        let (path, _dir) = test_database_path()?;
+        let keys = StaticKeyProvider::generate();
+
+        let vault = Vault::builder().path(&path).key_provider(keys.clone()).open().await?;
+        assert_eq!(vault.algorithm(), Algorithm::ChaCha20Poly1305);
+        vault.save("test", "test-key1", "test-value1", None, None).await?;
+        vault.pool().close().await;
+
+        let vault = Vault::builder()
+            .path(&path)
+            .key_provider(keys.clone())
+            .algorithm(Algorithm::XChaCha20Poly1305)
+            .open()
+            .await?;
+        vault.save("test", "test-key2", "test-value2", None, None).await?;
+        vault.pool().close().await;
+
+        let vault = Vault::builder().path(&path).key_provider(keys).open().await?;
+        assert_eq!(vault.algorithm(), Algorithm::XChaCha20Poly1305);
+        let entry = Entry::fetch(vault.pool(), "test", "test-key2").await?;
+        assert_eq!(entry.algorithm, "xchacha20-poly1305");
+        assert_eq!(entry.nonce.len(), 24);
+        assert_eq!(vault.fetch("test", "test-key1").await?.0, "test-value1");
+        assert_eq!(vault.fetch("test", "test-key2").await?.0, "test-value2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_vaults() -> Result<(), CacheVaultError> {
+        let (vault1, _vault1_dir) = open_test_vault().await?;
+        let (vault2, _vault2_dir) = open_test_vault().await?;
+        vault1.save("test", "test-key", "value-in-vault1", None, None).await?;
+        vault2.save("test", "test-key", "value-in-vault2", None, None).await?;
+        assert_eq!(vault1.fetch("test", "test-key").await?.0, "value-in-vault1");
+        assert_eq!(vault2.fetch("test", "test-key").await?.0, "value-in-vault2");
+        Ok(())
+    }
+}